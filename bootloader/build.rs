@@ -0,0 +1,14 @@
+//! Puts `memory.x` on the linker search path, like the firmware's build
+//! script, and sets the linker flags.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+	let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	fs::write(out.join("memory.x"), include_bytes!("memory.x")).unwrap();
+	println!("cargo:rustc-link-search={}", out.display());
+	println!("cargo:rerun-if-changed=memory.x");
+
+	println!("cargo:rustc-link-arg=--nmagic");
+	println!("cargo:rustc-link-arg=-Tlink.x");
+}
@@ -0,0 +1,30 @@
+/* The bootloader takes the first 24K of flash, then its state. ACTIVE,
+ * DFU and the settings sector must match `../memory-boot.x`, where the
+ * firmware's FLASH is ACTIVE here. */
+MEMORY {
+    BOOT2            : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH            : ORIGIN = 0x10000100, LENGTH = 24K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    ACTIVE           : ORIGIN = 0x10007000, LENGTH = 1004K
+    DFU              : ORIGIN = 0x10102000, LENGTH = 1008K
+    RAM              : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+EXTERN(BOOT2_FIRMWARE)
+
+SECTIONS {
+    /* ### Boot loader */
+    .boot2 ORIGIN(BOOT2) :
+    {
+        KEEP(*(.boot2));
+    } > BOOT2
+} INSERT BEFORE .text;
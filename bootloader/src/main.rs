@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Long enough to swap both partitions. If the bootloader hangs, the
+/// watchdog resets it to pick up where it left off.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+#[entry]
+fn main() -> ! {
+	let p = embassy_rp::init(Default::default());
+
+	let flash = WatchdogFlash::<FLASH_SIZE>::start(p.FLASH, p.WATCHDOG, WATCHDOG_TIMEOUT);
+	let flash = Mutex::new(RefCell::new(flash));
+
+	let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+	let active_offset = config.active.offset();
+	let bootloader: BootLoader = BootLoader::prepare(config);
+
+	unsafe { bootloader.load(embassy_rp::flash::FLASH_BASE as u32 + active_offset) }
+}
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+	cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+	cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+	cortex_m::peripheral::SCB::sys_reset();
+}
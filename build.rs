@@ -8,17 +8,47 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 //!
+//! With the `split-update` feature, `memory-boot.x` is used instead, which
+//! leaves room for the bootloader and its partitions.
+//!
 //! The build script also sets the linker flags to tell it which link script to use.
+//!
+//! It hashes the firmware sources into `BUILD_HASH` so that the
+//! two halves can tell whether they were flashed with the same build.
+//!
+//! It converts the OLED artwork under `frames/` into `frames.rs`,
+//! which `src/frames/mod.rs` includes. Each subdirectory becomes a group
+//! named after it (`frames/SIGILS/` is `frames::SIGILS`), holding its
+//! images in order of their trailing number. In a PNG, transparent pixels
+//! are left alone, and opaque ones are drawn lit if they're light and
+//! cleared if they're dark. A PBM has no transparency, so its set (black)
+//! pixels are drawn lit and everything else is left alone.
+//!
+//! Finally, it compresses the full-screen animations under `animations/`
+//! into `clips.rs`, which `src/clip.rs` includes. Each subdirectory is a
+//! clip named after it, made of 32x128 images named `<n>_<millis>ms`,
+//! shown in order of `n` for `millis` each. See `src/clip.rs` for the format.
 
-use std::{env, fs::File, io::Write, path::PathBuf};
+use std::{
+	env,
+	fmt::Write as _,
+	fs::{self, File},
+	io::Write,
+	path::{Path, PathBuf},
+};
 
 fn main() {
 	// Put `memory.x` in our output directory and ensure it's
 	// on the linker search path.
 	let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	let memory: &[u8] = if env::var_os("CARGO_FEATURE_SPLIT_UPDATE").is_some() {
+		include_bytes!("memory-boot.x")
+	} else {
+		include_bytes!("memory.x")
+	};
 	File::create(out.join("memory.x"))
 		.unwrap()
-		.write_all(include_bytes!("memory.x"))
+		.write_all(memory)
 		.unwrap();
 	println!("cargo:rustc-link-search={}", out.display());
 
@@ -27,16 +57,449 @@ fn main() {
 	// here, we ensure the build script is only re-run when
 	// `memory.x` is changed.
 	println!("cargo:rerun-if-changed=memory.x");
+	println!("cargo:rerun-if-changed=memory-boot.x");
+
+	// Specify linker arguments, unless this is a test build for the host.
+	if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+		// `--nmagic` is required if memory section addresses are not aligned to 0x10000,
+		// for example the FLASH and RAM sections in your `memory.x`.
+		// See https://github.com/rust-embedded/cortex-m-quickstart/pull/95
+		println!("cargo:rustc-link-arg=--nmagic");
+
+		// Set the linker script to the one provided by cortex-m-rt.
+		println!("cargo:rustc-link-arg=-Tlink.x");
+
+		println!("cargo:rustc-linker=flip-link");
+	}
+
+	let root = &PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+	let mut hash = FNV_OFFSET;
+	hash_file(&mut hash, root, &root.join("Cargo.toml"));
+	hash_file(&mut hash, root, &root.join("Cargo.lock"));
+	hash_dir(&mut hash, root, &root.join("src"));
+	hash_dir(&mut hash, root, &root.join("frames"));
+	hash_dir(&mut hash, root, &root.join("animations"));
+	File::create(out.join("build_hash.rs"))
+		.unwrap()
+		.write_all(format!("pub const BUILD_HASH: u32 = 0x{hash:08X};\n").as_bytes())
+		.unwrap();
+	println!("cargo:rerun-if-changed=Cargo.toml");
+	println!("cargo:rerun-if-changed=Cargo.lock");
+	println!("cargo:rerun-if-changed=src");
+
+	fs::write(out.join("frames.rs"), generate_frames(Path::new("frames"))).unwrap();
+	println!("cargo:rerun-if-changed=frames");
+
+	fs::write(
+		out.join("clips.rs"),
+		generate_clips(Path::new("animations"), out),
+	)
+	.unwrap();
+	println!("cargo:rerun-if-changed=animations");
+}
+
+const FNV_OFFSET: u32 = 0x811C_9DC5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+fn hash_bytes(hash: &mut u32, bytes: &[u8]) {
+	for &b in bytes {
+		*hash ^= b as u32;
+		*hash = hash.wrapping_mul(FNV_PRIME);
+	}
+}
+
+/// Hashes a file's path relative to `root`, with `/` separators, and its
+/// contents with CRLF line endings turned into LF, so that the same
+/// sources hash the same wherever and on whatever OS they're checked out.
+fn hash_file(hash: &mut u32, root: &Path, path: &Path) {
+	let name = path
+		.strip_prefix(root)
+		.unwrap()
+		.components()
+		.map(|component| component.as_os_str().to_string_lossy())
+		.collect::<Vec<_>>()
+		.join("/");
+	hash_bytes(hash, name.as_bytes());
+
+	let contents = fs::read(path).unwrap();
+	for (i, &b) in contents.iter().enumerate() {
+		if b == b'\r' && contents.get(i + 1) == Some(&b'\n') {
+			continue;
+		}
+		hash_bytes(hash, &[b]);
+	}
+}
+
+fn hash_dir(hash: &mut u32, root: &Path, path: &Path) {
+	// Sorted, so the hash doesn't depend on directory iteration order.
+	for entry in sorted_entries(path) {
+		if entry.is_dir() {
+			hash_dir(hash, root, &entry);
+		} else {
+			hash_file(hash, root, &entry);
+		}
+	}
+}
+
+fn sorted_entries(path: &Path) -> Vec<PathBuf> {
+	let mut entries = fs::read_dir(path)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.collect::<Vec<_>>();
+	entries.sort();
+	entries
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pixel {
+	Transparent,
+	Off,
+	On,
+}
+
+struct Image {
+	width:  usize,
+	height: usize,
+	pixels: Vec<Pixel>,
+}
+
+fn generate_frames(dir: &Path) -> String {
+	let mut code = String::new();
+
+	for group in sorted_entries(dir) {
+		if !group.is_dir() {
+			continue;
+		}
+
+		let mut images = sorted_entries(&group);
+		images.sort_by_key(|path| (frame_number(path), path.clone()));
+
+		let mut names = Vec::new();
+
+		for path in images {
+			let image = match path.extension().and_then(|ext| ext.to_str()) {
+				Some("png") => load_png(&path),
+				Some("pbm") => load_pbm(&path),
+				_ => continue,
+			};
+
+			let name = file_stem(&path).to_lowercase();
+			let (mask, add) = pack(&image);
+
+			writeln!(code, "pub mod {name} {{").unwrap();
+			writeln!(code, "\tpub const WIDTH: usize = {};", image.width).unwrap();
+			writeln!(code, "\tpub const HEIGHT: usize = {};", image.height).unwrap();
+			writeln!(code, "\tpub const MASK: &[u8] = &{mask:?};").unwrap();
+			writeln!(code, "\tpub const ADD: &[u8] = &{add:?};").unwrap();
+			writeln!(code, "}}").unwrap();
+
+			names.push(name);
+		}
+
+		let group = file_stem(&group).to_uppercase();
+		writeln!(code, "frames!({group} = [{}]);", names.join(", ")).unwrap();
+	}
+
+	code
+}
+
+fn file_stem(path: &Path) -> String {
+	path.file_stem().unwrap().to_string_lossy().into_owned()
+}
+
+/// The number after the last underscore, so that `sigil_10` sorts
+/// after `sigil_9`.
+fn frame_number(path: &Path) -> u32 {
+	file_stem(path)
+		.rsplit('_')
+		.next()
+		.and_then(|number| number.parse().ok())
+		.unwrap_or(0)
+}
+
+/// Packs an image into the mask and add planes of a `Frame`: rows of
+/// whole bytes, leftmost pixel in the most significant bit.
+fn pack(image: &Image) -> (Vec<u8>, Vec<u8>) {
+	let stride = image.width.div_ceil(8);
+	let mut mask = vec![0; stride * image.height];
+	let mut add = vec![0; stride * image.height];
+
+	for y in 0..image.height {
+		for x in 0..image.width {
+			let byte = y * stride + x / 8;
+			let bit = 0x80 >> (x % 8);
+
+			match image.pixels[y * image.width + x] {
+				Pixel::Transparent => {}
+				Pixel::Off => mask[byte] |= bit,
+				Pixel::On => {
+					mask[byte] |= bit;
+					add[byte] |= bit;
+				}
+			}
+		}
+	}
+
+	(mask, add)
+}
+
+fn load_png(path: &Path) -> Image {
+	let mut decoder = png::Decoder::new(File::open(path).unwrap());
+	// Palettes, `tRNS` transparency and low bit depths all become plain
+	// 8-bit gray or RGB, with or without alpha.
+	decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+	let mut reader = decoder
+		.read_info()
+		.unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+	let mut buf = vec![0; reader.output_buffer_size()];
+	let info = reader
+		.next_frame(&mut buf)
+		.unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+	let samples = info.color_type.samples();
+
+	let mut pixels = Vec::with_capacity(info.width as usize * info.height as usize);
+
+	for row in buf.chunks(info.line_size).take(info.height as usize) {
+		for sample in row.chunks(samples).take(info.width as usize) {
+			let (light, alpha) = match *sample {
+				[gray] => (gray as u32, 255),
+				[gray, alpha] => (gray as u32, alpha),
+				[r, g, b] => (luma(r, g, b), 255),
+				[r, g, b, alpha] => (luma(r, g, b), alpha),
+				_ => unreachable!(),
+			};
+
+			pixels.push(
+				if alpha < 128 {
+					Pixel::Transparent
+				} else if light >= 128 {
+					Pixel::On
+				} else {
+					Pixel::Off
+				},
+			);
+		}
+	}
+
+	Image {
+		width: info.width as usize,
+		height: info.height as usize,
+		pixels,
+	}
+}
+
+/// Screen size of the clips, matching `framebuffer::WIDTH` and `HEIGHT`.
+const CLIP_WIDTH: usize = 32;
+const CLIP_HEIGHT: usize = 128;
+const CLIP_PLANE_SIZE: usize = CLIP_WIDTH * CLIP_HEIGHT / 8;
+
+fn generate_clips(dir: &Path, out: &Path) -> String {
+	let mut code = String::new();
+
+	for clip in sorted_entries(dir) {
+		if !clip.is_dir() {
+			continue;
+		}
+
+		let mut frames = Vec::new();
+
+		for path in sorted_entries(&clip) {
+			let image = match path.extension().and_then(|ext| ext.to_str()) {
+				Some("png") => load_png(&path),
+				Some("pbm") => load_pbm(&path),
+				_ => continue,
+			};
+
+			assert!(
+				image.width == CLIP_WIDTH && image.height == CLIP_HEIGHT,
+				"{}: clip frames must be {CLIP_WIDTH}x{CLIP_HEIGHT}",
+				path.display()
+			);
+
+			let stem = file_stem(&path);
+			let timing = stem
+				.split_once('_')
+				.and_then(|(n, millis)| {
+					Some((
+						n.parse::<u32>().ok()?,
+						millis.strip_suffix("ms")?.parse::<u16>().ok()?,
+					))
+				})
+				.unwrap_or_else(|| panic!("{}: expected <n>_<millis>ms", path.display()));
+
+			frames.push((timing, pack_screen(&image)));
+		}
+
+		frames.sort_by_key(|&((n, _), _)| n);
+
+		let name = file_stem(&clip).to_uppercase();
+		let file = out.join(format!("{}.clip", name.to_lowercase()));
+		fs::write(&file, encode_clip(&frames)).unwrap();
+
+		writeln!(
+			code,
+			"pub const {name}: Clip = Clip {{ data: include_bytes!({:?}) }};",
+			file.display().to_string()
+		)
+		.unwrap();
+	}
+
+	code
+}
+
+/// Packs a full-screen image into its mask and add planes, laid out like
+/// the OLED buffer: rows of 4 bytes, leftmost pixel in the least
+/// significant bit.
+fn pack_screen(image: &Image) -> Vec<u8> {
+	let mut planes = vec![0; CLIP_PLANE_SIZE * 2];
+
+	for (idx, pixel) in image.pixels.iter().enumerate() {
+		let bit = 1 << (idx % 8);
+
+		match pixel {
+			Pixel::Transparent => {}
+			Pixel::Off => planes[idx / 8] |= bit,
+			Pixel::On => {
+				planes[idx / 8] |= bit;
+				planes[CLIP_PLANE_SIZE + idx / 8] |= bit;
+			}
+		}
+	}
+
+	planes
+}
+
+/// Each frame is its duration in milliseconds and the length of its
+/// body (both little-endian `u16`s), then the body: the run-length coded
+/// difference from the previous frame (or from nothing, for the first).
+fn encode_clip(frames: &[((u32, u16), Vec<u8>)]) -> Vec<u8> {
+	let mut data = Vec::new();
+	let mut previous = vec![0; CLIP_PLANE_SIZE * 2];
+
+	for ((_, millis), planes) in frames {
+		let delta = planes
+			.iter()
+			.zip(&previous)
+			.map(|(a, b)| a ^ b)
+			.collect::<Vec<u8>>();
+		let body = encode_runs(&delta);
+
+		data.extend(millis.to_le_bytes());
+		data.extend(u16::try_from(body.len()).unwrap().to_le_bytes());
+		data.extend(body);
+
+		previous.clone_from(planes);
+	}
+
+	data
+}
+
+/// A control byte with the top bit clear skips that many bytes plus one;
+/// with it set, that many bytes plus one follow, to be XORed in.
+fn encode_runs(delta: &[u8]) -> Vec<u8> {
+	let mut body = Vec::new();
+	// Nothing after the last change needs encoding.
+	let end = delta
+		.iter()
+		.rposition(|&b| b != 0)
+		.map_or(0, |last| last + 1);
+	let mut i = 0;
+
+	while i < end {
+		let run = if delta[i] == 0 {
+			let run = delta[i..end]
+				.iter()
+				.take(128)
+				.take_while(|&&b| b == 0)
+				.count();
+			body.push(run as u8 - 1);
+			run
+		} else {
+			// Two unchanged bytes in a row are cheaper skipped than copied.
+			let mut run = 0;
+			while i + run < end
+				&& run < 128 && !(delta[i + run] == 0
+				&& delta.get(i + run + 1).is_none_or(|&b| b == 0))
+			{
+				run += 1;
+			}
+			body.push(0x80 | (run as u8 - 1));
+			body.extend(&delta[i..i + run]);
+			run
+		};
+
+		i += run;
+	}
+
+	body
+}
+
+fn luma(r: u8, g: u8, b: u8) -> u32 {
+	(r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+}
+
+/// Reads a plain (`P1`) or raw (`P4`) PBM.
+fn load_pbm(path: &Path) -> Image {
+	let data = fs::read(path).unwrap();
+	let mut pos = 0;
+
+	let token = |pos: &mut usize| {
+		loop {
+			match data.get(*pos) {
+				Some(b'#') => {
+					while data.get(*pos).is_some_and(|&c| c != b'\n') {
+						*pos += 1;
+					}
+				}
+				Some(c) if c.is_ascii_whitespace() => *pos += 1,
+				_ => break,
+			}
+		}
+
+		let start = *pos;
+		while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+			*pos += 1;
+		}
+		String::from_utf8_lossy(&data[start..*pos]).into_owned()
+	};
 
-	// Specify linker arguments.
+	let magic = token(&mut pos);
+	let size = [token(&mut pos), token(&mut pos)].map(|n| {
+		n.parse::<usize>()
+			.unwrap_or_else(|_| panic!("{}: bad size", path.display()))
+	});
+	let [width, height] = size;
 
-	// `--nmagic` is required if memory section addresses are not aligned to 0x10000,
-	// for example the FLASH and RAM sections in your `memory.x`.
-	// See https://github.com/rust-embedded/cortex-m-quickstart/pull/95
-	println!("cargo:rustc-link-arg=--nmagic");
+	let bits: Vec<bool> = match magic.as_str() {
+		"P1" => {
+			data[pos..]
+				.iter()
+				.filter(|c| matches!(c, b'0' | b'1'))
+				.map(|&c| c == b'1')
+				.take(width * height)
+				.collect()
+		}
+		"P4" => {
+			// Exactly one whitespace character follows the height.
+			let stride = width.div_ceil(8);
+			data[pos + 1..]
+				.chunks(stride)
+				.take(height)
+				.flat_map(|row| (0..width).map(move |x| row[x / 8] & (0x80 >> (x % 8)) != 0))
+				.collect()
+		}
+		_ => panic!("{}: not a PBM", path.display()),
+	};
 
-	// Set the linker script to the one provided by cortex-m-rt.
-	println!("cargo:rustc-link-arg=-Tlink.x");
+	assert_eq!(bits.len(), width * height, "{}: truncated", path.display());
 
-	println!("cargo:rustc-linker=flip-link");
+	Image {
+		width,
+		height,
+		pixels: bits
+			.into_iter()
+			.map(|set| if set { Pixel::On } else { Pixel::Transparent })
+			.collect(),
+	}
 }
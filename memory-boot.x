@@ -0,0 +1,23 @@
+/* Layout for the `split-update` feature. embassy-boot's rp bootloader
+ * (`bootloader/`) lives in the first 24K and must be flashed before
+ * this firmware; it provides boot2, so there's no `.boot2` section here.
+ * The last 4K sector holds the saved display settings.
+ *
+ * The settings sector shrank ACTIVE and DFU by 4K each, so a bootloader
+ * built for the earlier layout (ACTIVE 1008K, DFU at 0x10103000) would
+ * swap the wrong regions. Rebuild and reflash it (`cargo make
+ * uf2-bootloader`, with BOOTSEL) along with this firmware, and keep
+ * `bootloader/memory.x` in step with any change here. */
+MEMORY {
+    BOOT2            : ORIGIN = 0x10000000, LENGTH = 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH            : ORIGIN = 0x10007000, LENGTH = 1004K
+    DFU              : ORIGIN = 0x10102000, LENGTH = 1008K
+    RAM              : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
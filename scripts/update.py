@@ -0,0 +1,116 @@
+#!/usr/bin/env python3
+"""Updates the firmware of the half that isn't plugged in.
+
+Writes a raw image (as made by `cargo objcopy -- -O binary`) to the
+update interface of the USB-connected half, which passes it on over the
+split link (see `src/update.rs`). Both halves must already run firmware
+built with the `split-update` feature, under embassy-boot's bootloader.
+
+Needs hidapi: `pip install hidapi`.
+"""
+
+import argparse
+import struct
+import sys
+import time
+import zlib
+
+import hid
+
+VENDOR_ID = 0x1337
+PRODUCT_ID = 0xA1C4
+# The update interface's report descriptor (`UpdateReport` in `src/usb.rs`).
+USAGE_PAGE = 0xFF00
+USAGE = 0x02
+
+CMD_BEGIN = 0x01
+CMD_DATA = 0x02
+REPORT_SIZE = 64
+DATA_SIZE = REPORT_SIZE - 2
+
+STATE_IDLE = 0
+STATE_SENDING = 1
+STATE_VERIFYING = 2
+STATE_DONE = 3
+STATE_FAILED = 4
+
+# Long enough for the other half to check the image (`VERIFY_TIMEOUT`).
+DONE_TIMEOUT = 15
+START_TIMEOUT = 1
+
+
+def open_device():
+    for info in hid.enumerate(VENDOR_ID, PRODUCT_ID):
+        if info["usage_page"] == USAGE_PAGE and info["usage"] == USAGE:
+            device = hid.device()
+            device.open_path(info["path"])
+            return device
+
+    sys.exit("No keyboard with the update interface found; is it built with `split-update`?")
+
+
+def write_report(device, report):
+    # No report IDs, so the report number is 0.
+    device.write([0] + list(report.ljust(REPORT_SIZE, b"\0")))
+
+
+def status(device):
+    """Returns the state, the number of updates begun (wrapping at 256) and
+    the progress of the current one."""
+    # Some platforms prefix the report number, others don't.
+    report = bytes(device.get_feature_report(0, 7))[-6:]
+    return struct.unpack("<BBI", report)
+
+
+def wait_for(device, attempt, states, timeout):
+    deadline = time.monotonic() + timeout
+
+    while time.monotonic() < deadline:
+        state, current, progress = status(device)
+        if current == attempt and state in states:
+            return state, progress
+        time.sleep(0.05)
+
+    sys.exit("Timed out waiting for the keyboard")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("image", help="raw firmware image for the other half")
+    args = parser.parse_args()
+
+    with open(args.image, "rb") as f:
+        image = f.read()
+
+    device = open_device()
+
+    # Until the keyboard gets to the new update, the state is the last one's.
+    _, attempt, _ = status(device)
+    attempt = (attempt + 1) % 256
+
+    write_report(device, struct.pack("<BII", CMD_BEGIN, len(image), zlib.crc32(image)))
+    state, _ = wait_for(device, attempt, (STATE_SENDING, STATE_FAILED), START_TIMEOUT)
+    if state == STATE_FAILED:
+        sys.exit("The other half isn't connected")
+
+    for offset in range(0, len(image), DATA_SIZE):
+        data = image[offset : offset + DATA_SIZE]
+        write_report(device, bytes([CMD_DATA, len(data)]) + data)
+
+        # Writes block while the other half is busy, so this only
+        # reports what's been acknowledged.
+        state, _, progress = status(device)
+        if state == STATE_FAILED:
+            sys.exit(f"Failed after {progress} of {len(image)} bytes")
+        print(f"\r{progress * 100 // len(image)}%", end="", flush=True)
+
+    print("\rVerifying...")
+    state, _ = wait_for(device, attempt, (STATE_DONE, STATE_FAILED), DONE_TIMEOUT)
+    if state == STATE_FAILED:
+        sys.exit("The other half rejected the image")
+
+    print("Done; the other half is restarting with the new firmware.")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,164 @@
+//! Plays the artwork in `frames` over the starfield.
+//!
+//! An animation is a list of steps, each showing some frames for a while.
+//! The idle animation loops whenever nothing else is playing; the others
+//! play once when triggered and then hand back to it. Longer, full-screen
+//! animations are played from compressed clips instead (see `clip`).
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+	clip::{self, ClipPlayer},
+	frames::{self, Frame},
+};
+
+/// A frame and where to put it. `x` is rounded down to a multiple of 8.
+pub struct Sprite {
+	pub frame: &'static Frame,
+	pub x:     usize,
+	pub y:     usize,
+}
+
+pub struct Step {
+	pub sprites:  &'static [Sprite],
+	pub duration: Duration,
+}
+
+pub struct Animation {
+	pub steps:   &'static [Step],
+	pub looping: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+	Boot,
+	LayerChange,
+	/// Only ever interrupts the idle animation.
+	KeyPress,
+}
+
+static TRIGGERS: Channel<CriticalSectionRawMutex, Trigger, 4> = Channel::new();
+
+// Below the master's status (see `oled::draw_status`), and clear of it.
+const SIGIL_X: usize = 16;
+const SIGIL_Y: usize = 86;
+const BODY_Y: usize = 96;
+
+/// A step showing each `frame, x, y` for `millis`.
+macro_rules! step {
+	($millis:expr => $($frame:expr, $x:expr, $y:expr);+ $(;)?) => {
+		Step {
+			sprites:  &[$(Sprite { frame: &$frame, x: $x, y: $y }),+],
+			duration: Duration::from_millis($millis),
+		}
+	};
+}
+
+static IDLE: Animation = Animation {
+	steps:   &[
+		step!(600 => frames::BODY[0], 0, BODY_Y),
+		step!(600 => frames::BODY[1], 0, BODY_Y),
+	],
+	looping: true,
+};
+
+/// Every sigil in turn.
+static LAYER_CHANGE: Animation = Animation {
+	steps:   &[
+		step!(60 => frames::SIGILS[0], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[1], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[2], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[3], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[4], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[5], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[6], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[7], SIGIL_X, SIGIL_Y),
+		step!(60 => frames::SIGILS[8], SIGIL_X, SIGIL_Y),
+		step!(200 => frames::SIGILS[9], SIGIL_X, SIGIL_Y),
+	],
+	looping: false,
+};
+
+static KEY_PRESS: Animation = Animation {
+	steps:   &[
+		step!(40 => frames::SIGILS[0], SIGIL_X, SIGIL_Y),
+		step!(40 => frames::SIGILS[1], SIGIL_X, SIGIL_Y),
+		step!(40 => frames::SIGILS[2], SIGIL_X, SIGIL_Y),
+	],
+	looping: false,
+};
+
+/// Starts an animation from any task; it shows up on the next frame.
+pub fn trigger(trigger: Trigger) {
+	TRIGGERS.try_send(trigger).ok();
+}
+
+pub struct Player {
+	animation: &'static Animation,
+	step:      usize,
+	step_end:  Instant,
+	/// Shown instead of the steps while it lasts.
+	clip:      Option<ClipPlayer>,
+}
+
+impl Player {
+	pub fn new(now: Instant) -> Self {
+		Self {
+			animation: &IDLE,
+			step:      0,
+			step_end:  now + IDLE.steps[0].duration,
+			clip:      None,
+		}
+	}
+
+	fn play(&mut self, animation: &'static Animation, now: Instant) {
+		self.clip = None;
+		self.animation = animation;
+		self.step = 0;
+		self.step_end = now + animation.steps[0].duration;
+	}
+
+	/// Starts any triggered animation and returns what to draw this frame.
+	pub fn update(&mut self, now: Instant) -> &'static [Sprite] {
+		while let Ok(trigger) = TRIGGERS.try_receive() {
+			match trigger {
+				Trigger::Boot => {
+					self.play(&IDLE, now);
+					self.clip = Some(ClipPlayer::new(&clip::BOOT, now));
+				}
+				Trigger::LayerChange => self.play(&LAYER_CHANGE, now),
+				Trigger::KeyPress if self.animation.looping && self.clip.is_none() => {
+					self.play(&KEY_PRESS, now)
+				}
+				Trigger::KeyPress => {}
+			}
+		}
+
+		if let Some(clip) = &mut self.clip {
+			if clip.update(now) {
+				return &[];
+			}
+
+			self.play(&IDLE, now);
+		}
+
+		if now >= self.step_end {
+			if self.step + 1 < self.animation.steps.len() {
+				self.step += 1;
+				self.step_end = now + self.animation.steps[self.step].duration;
+			} else if self.animation.looping {
+				self.play(self.animation, now);
+			} else {
+				self.play(&IDLE, now);
+			}
+		}
+
+		self.animation.steps[self.step].sprites
+	}
+
+	/// The clip to draw over the sprites, if one is playing.
+	pub fn clip(&self) -> Option<&ClipPlayer> {
+		self.clip.as_ref()
+	}
+}
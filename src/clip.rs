@@ -0,0 +1,93 @@
+//! Compressed full-screen animations, generated by `build.rs` from the
+//! images in `animations/`.
+//!
+//! A clip is a sequence of frames, each a `u16` duration in milliseconds,
+//! a `u16` body length (both little-endian) and the body. Decoded, a frame
+//! is a mask and an add plane laid out like the OLED buffer; the body
+//! holds what changed since the previous frame, as control bytes each
+//! followed by their run: with the top bit clear, skip the low bits plus
+//! one bytes; with it set, XOR in the low bits plus one bytes that follow.
+
+use embassy_time::{Duration, Instant};
+
+use crate::framebuffer::SIZE;
+
+pub struct Clip {
+	pub data: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/clips.rs"));
+
+/// Decodes a clip a frame at a time, as it's shown.
+pub struct ClipPlayer {
+	data:      &'static [u8],
+	pos:       usize,
+	mask:      [u8; SIZE],
+	add:       [u8; SIZE],
+	frame_end: Instant,
+}
+
+impl ClipPlayer {
+	pub fn new(clip: &Clip, now: Instant) -> Self {
+		let mut player = Self {
+			data:      clip.data,
+			pos:       0,
+			mask:      [0; SIZE],
+			add:       [0; SIZE],
+			frame_end: now,
+		};
+		player.update(now);
+		player
+	}
+
+	/// Moves on to the next frame once the current one has been shown for
+	/// long enough. Returns `false` once the last frame is over.
+	pub fn update(&mut self, now: Instant) -> bool {
+		if now < self.frame_end {
+			return true;
+		}
+
+		if self.pos + 4 > self.data.len() {
+			return false;
+		}
+
+		let header = &self.data[self.pos..self.pos + 4];
+		let millis = u16::from_le_bytes([header[0], header[1]]);
+		let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+		let body = &self.data[self.pos + 4..self.pos + 4 + len];
+		self.pos += 4 + len;
+
+		let mut out = 0;
+		let mut i = 0;
+
+		while i < body.len() {
+			let control = body[i];
+			let run = (control & 0x7F) as usize + 1;
+			i += 1;
+
+			if control & 0x80 != 0 {
+				for &byte in &body[i..i + run] {
+					if out < SIZE {
+						self.mask[out] ^= byte;
+					} else {
+						self.add[out - SIZE] ^= byte;
+					}
+					out += 1;
+				}
+				i += run;
+			} else {
+				out += run;
+			}
+		}
+
+		self.frame_end = now + Duration::from_millis(millis as u64);
+		true
+	}
+
+	/// Draws the current frame over whatever is in `buffer`.
+	pub fn draw(&self, buffer: &mut [u8; SIZE]) {
+		for ((byte, mask), add) in buffer.iter_mut().zip(&self.mask).zip(&self.add) {
+			*byte = (*byte & !mask) | add;
+		}
+	}
+}
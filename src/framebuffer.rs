@@ -0,0 +1,97 @@
+//! The OLED framebuffer as an `embedded-graphics` draw target.
+//!
+//! The display is mounted vertically, so the buffer is 32 pixels wide and
+//! 128 tall, one bit per pixel, row by row with four bytes to a row and
+//! the leftmost pixel in the least significant bit. With the display in
+//! vertical addressing mode that's exactly the order it wants the bytes.
+
+use core::convert::Infallible;
+
+use embedded_graphics::{
+	Pixel,
+	pixelcolor::BinaryColor,
+	prelude::{DrawTarget, OriginDimensions, Point, Size},
+	primitives::{PointsIter, Rectangle},
+};
+
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 128;
+pub const SIZE: usize = WIDTH * HEIGHT / 8;
+
+pub struct Framebuffer<'a> {
+	buffer: &'a mut [u8; SIZE],
+}
+
+impl<'a> Framebuffer<'a> {
+	pub fn new(buffer: &'a mut [u8; SIZE]) -> Self {
+		Self { buffer }
+	}
+
+	/// The raw bytes, for sending to the display or blitting into.
+	pub fn buffer(&mut self) -> &mut [u8; SIZE] {
+		self.buffer
+	}
+
+	pub fn set_pixel(&mut self, point: Point, color: BinaryColor) {
+		if point.x < 0 || point.y < 0 || point.x >= WIDTH as i32 || point.y >= HEIGHT as i32 {
+			return;
+		}
+
+		let idx = point.y as usize * WIDTH + point.x as usize;
+		let mask = 1 << (idx % 8);
+
+		match color {
+			BinaryColor::On => self.buffer[idx / 8] |= mask,
+			BinaryColor::Off => self.buffer[idx / 8] &= !mask,
+		}
+	}
+}
+
+impl OriginDimensions for Framebuffer<'_> {
+	fn size(&self) -> Size {
+		Size::new(WIDTH as u32, HEIGHT as u32)
+	}
+}
+
+impl DrawTarget for Framebuffer<'_> {
+	type Color = BinaryColor;
+	type Error = Infallible;
+
+	fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Pixel<Self::Color>>,
+	{
+		for Pixel(point, color) in pixels {
+			self.set_pixel(point, color);
+		}
+
+		Ok(())
+	}
+
+	fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+		let area = area.intersection(&Rectangle::new(Point::zero(), self.size()));
+
+		// Whole rows are whole bytes.
+		if area.top_left.x == 0 && area.size.width == WIDTH as u32 {
+			let start = area.top_left.y as usize * WIDTH / 8;
+			let end = start + area.size.height as usize * WIDTH / 8;
+			let fill = match color {
+				BinaryColor::On => 0xFF,
+				BinaryColor::Off => 0x00,
+			};
+
+			self.buffer[start..end].fill(fill);
+			return Ok(());
+		}
+
+		for point in area.points() {
+			self.set_pixel(point, color);
+		}
+
+		Ok(())
+	}
+
+	fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+		self.fill_solid(&Rectangle::new(Point::zero(), self.size()), color)
+	}
+}
@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 32;
-pub const HEIGHT: usize = 32;
-pub const MASK: &[u8] = include_bytes!("body_1_mask.bit");
-pub const ADD: &[u8] = include_bytes!("body_1_add.bit");
@@ -8,10 +8,6 @@ pub struct Frame {
 
 macro_rules! frames {
 	($id:ident = [$($frame_name:ident),* $(,)?]) => {
-		$(
-			pub mod $frame_name;
-		)*
-
 		#[allow(dead_code)]
 		pub const $id: &[Frame] = &[
 			$(
@@ -26,10 +22,6 @@ macro_rules! frames {
 	};
 }
 
-frames!(
-	SIGILS = [
-		sigil_1, sigil_2, sigil_3, sigil_4, sigil_5, sigil_6, sigil_7, sigil_8, sigil_9, sigil_10
-	]
-);
-frames!(BANNER = [banner_1, banner_2]);
-frames!(BODY = [body_1, body_2]);
+// The frame modules and their `frames!` registrations, generated by
+// `build.rs` from the images in `frames/`.
+include!(concat!(env!("OUT_DIR"), "/frames.rs"));
@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 4;
-pub const HEIGHT: usize = 4;
-pub const MASK: &[u8] = include_bytes!("sigil_1_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_1_add.bit");
@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 7;
-pub const HEIGHT: usize = 5;
-pub const MASK: &[u8] = include_bytes!("sigil_3_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_3_add.bit");
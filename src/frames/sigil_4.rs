@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 6;
-pub const HEIGHT: usize = 4;
-pub const MASK: &[u8] = include_bytes!("sigil_4_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_4_add.bit");
@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 6;
-pub const HEIGHT: usize = 6;
-pub const MASK: &[u8] = include_bytes!("sigil_6_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_6_add.bit");
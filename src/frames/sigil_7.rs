@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 4;
-pub const HEIGHT: usize = 5;
-pub const MASK: &[u8] = include_bytes!("sigil_7_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_7_add.bit");
@@ -1,4 +0,0 @@
-pub const WIDTH: usize = 3;
-pub const HEIGHT: usize = 3;
-pub const MASK: &[u8] = include_bytes!("sigil_9_mask.bit");
-pub const ADD: &[u8] = include_bytes!("sigil_9_add.bit");
@@ -0,0 +1,352 @@
+//! Framing for the split link.
+//!
+//! Every frame is a sync byte, the payload length, a type, a sequence
+//! number, the payload and a CRC-16 of everything between the sync byte
+//! and the CRC (big-endian). What the types and payloads mean is up to
+//! `uart`; this only gets whole, intact frames across a byte stream.
+
+/// Marks the start of every frame on the wire.
+pub const SYNC: u8 = 0xA5;
+/// The largest payload a single frame can carry.
+pub const MAX_PAYLOAD: usize = 64;
+/// Sync byte, length, type, sequence number, payload and a 16-bit CRC.
+pub const MAX_FRAME_SIZE: usize = MAX_PAYLOAD + 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+	/// A well-formed frame failed its checksum.
+	Crc,
+	/// A sync byte wasn't followed by a sensible frame.
+	Malformed,
+}
+
+/// Frames a payload for the wire, returning the frame's length.
+pub fn encode_frame(kind: u8, seq: u8, payload: &[u8], frame: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+	let len = payload.len();
+
+	frame[0] = SYNC;
+	frame[1] = len as u8;
+	frame[2] = kind;
+	frame[3] = seq;
+	frame[4..4 + len].copy_from_slice(payload);
+
+	let crc = crc16(&frame[1..4 + len]);
+	frame[4 + len..6 + len].copy_from_slice(&crc.to_be_bytes());
+
+	6 + len
+}
+
+/// How many bytes at the start of `bytes`, as written by a sender of whole
+/// frames, make up whole frames. Anything that doesn't look like a frame
+/// counts as whole, so that it's passed on rather than held up forever.
+pub fn whole_frames_len(bytes: &[u8]) -> usize {
+	let mut len = 0;
+
+	while len < bytes.len() {
+		let rest = &bytes[len..];
+		if rest[0] != SYNC {
+			return bytes.len();
+		}
+
+		let Some(&payload_len) = rest.get(1) else {
+			break;
+		};
+		if payload_len as usize > MAX_PAYLOAD {
+			return bytes.len();
+		}
+
+		let frame_len = payload_len as usize + 6;
+		if rest.len() < frame_len {
+			break;
+		}
+		len += frame_len;
+	}
+
+	len
+}
+
+/// CRC-16/CCITT-FALSE.
+fn crc16(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0xFFFF;
+	for &byte in data {
+		crc ^= (byte as u16) << 8;
+		for _ in 0..8 {
+			crc = if crc & 0x8000 != 0 {
+				(crc << 1) ^ 0x1021
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+/// Reassembles frames from a byte stream.
+///
+/// Bytes are fed in with `push()` and complete frames taken out with
+/// `decode()`. Whenever a frame turns out to be bad (oversized, failing
+/// its CRC or of an unknown type), only its sync byte is discarded and
+/// the stream is rescanned from the following byte, so a dropped or
+/// corrupted byte costs at most the frames it overlaps.
+pub struct Decoder {
+	buf: [u8; MAX_FRAME_SIZE],
+	len: usize,
+}
+
+impl Default for Decoder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Decoder {
+	pub const fn new() -> Self {
+		Decoder {
+			buf: [0; MAX_FRAME_SIZE],
+			len: 0,
+		}
+	}
+
+	/// Buffers a byte. Call `decode()` until it returns `None` afterwards.
+	pub fn push(&mut self, byte: u8) {
+		if self.len == self.buf.len() {
+			// Can't happen with a consistent decoder, but never panic on line noise.
+			self.skip(1);
+		}
+
+		self.buf[self.len] = byte;
+		self.len += 1;
+	}
+
+	/// Takes out the next complete frame, if there is one. `parse` turns
+	/// its type, sequence number and payload into a frame, or returns
+	/// `None` if they make no sense together.
+	pub fn decode<T>(
+		&mut self,
+		parse: impl FnOnce(u8, u8, &[u8]) -> Option<T>,
+	) -> Option<Result<T, DecodeError>> {
+		// Drop everything up to the next sync byte.
+		let start = self.buf[..self.len]
+			.iter()
+			.position(|&b| b == SYNC)
+			.unwrap_or(self.len);
+		self.skip(start);
+
+		if self.len < 2 {
+			return None;
+		}
+
+		let payload_len = self.buf[1] as usize;
+		if payload_len > MAX_PAYLOAD {
+			self.skip(1);
+			return Some(Err(DecodeError::Malformed));
+		}
+
+		let frame_len = payload_len + 6;
+		if self.len < frame_len {
+			return None;
+		}
+
+		let crc = u16::from_be_bytes([self.buf[frame_len - 2], self.buf[frame_len - 1]]);
+		if crc != crc16(&self.buf[1..frame_len - 2]) {
+			self.skip(1);
+			return Some(Err(DecodeError::Crc));
+		}
+
+		match parse(self.buf[2], self.buf[3], &self.buf[4..frame_len - 2]) {
+			Some(frame) => {
+				self.skip(frame_len);
+				Some(Ok(frame))
+			}
+			None => {
+				self.skip(1);
+				Some(Err(DecodeError::Malformed))
+			}
+		}
+	}
+
+	/// Gives up on a partially received frame once the line has gone
+	/// idle, since a corrupted length can otherwise stall the decoder
+	/// until enough unrelated bytes arrive to fill it.
+	pub fn flush(&mut self) {
+		if self.len > 0 {
+			self.skip(1);
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	fn skip(&mut self, count: usize) {
+		self.buf.copy_within(count..self.len, 0);
+		self.len -= count;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type Decoded = Result<(u8, u8, Vec<u8>), DecodeError>;
+
+	fn frame(kind: u8, seq: u8, payload: &[u8]) -> Vec<u8> {
+		let mut frame = [0; MAX_FRAME_SIZE];
+		let len = encode_frame(kind, seq, payload, &mut frame);
+		frame[..len].to_vec()
+	}
+
+	/// Feeds `bytes` in one at a time, taking out everything decoded, and
+	/// then lets the line go idle. Only frames of type 1 are understood.
+	fn decode_all(bytes: &[u8]) -> Vec<Decoded> {
+		let mut decoder = Decoder::new();
+		let mut decoded = Vec::new();
+
+		let mut drain = |decoder: &mut Decoder| {
+			while let Some(result) = decoder
+				.decode(|kind, seq, payload| (kind == 1).then(|| (kind, seq, payload.to_vec())))
+			{
+				decoded.push(result);
+			}
+		};
+
+		for &byte in bytes {
+			decoder.push(byte);
+			drain(&mut decoder);
+		}
+
+		while !decoder.is_empty() {
+			decoder.flush();
+			drain(&mut decoder);
+		}
+
+		decoded
+	}
+
+	fn frames(decoded: &[Decoded]) -> Vec<(u8, u8, Vec<u8>)> {
+		decoded
+			.iter()
+			.filter_map(|r| r.as_ref().ok().cloned())
+			.collect()
+	}
+
+	#[test]
+	fn back_to_back_frames() {
+		let mut bytes = frame(1, 1, &[1, 2, 3]);
+		bytes.extend(frame(1, 2, &[]));
+		bytes.extend(frame(1, 3, &[0; MAX_PAYLOAD]));
+
+		assert_eq!(
+			decode_all(&bytes),
+			[
+				Ok((1, 1, vec![1, 2, 3])),
+				Ok((1, 2, vec![])),
+				Ok((1, 3, vec![0; MAX_PAYLOAD])),
+			]
+		);
+	}
+
+	#[test]
+	fn flipped_bit_is_rejected() {
+		let original = frame(1, 7, &[0x10, 0x20]);
+
+		// Anything but the sync byte, which would just look like noise.
+		for bit in 8..original.len() * 8 {
+			let mut bytes = original.clone();
+			bytes[bit / 8] ^= 1 << (bit % 8);
+			bytes.extend(frame(1, 8, &[0x30]));
+
+			// A longer length waits for the line to go idle instead of
+			// failing, but either way only the intact frame gets through.
+			assert_eq!(
+				frames(&decode_all(&bytes)),
+				[(1, 8, vec![0x30])],
+				"bit {bit}"
+			);
+		}
+	}
+
+	#[test]
+	fn flipped_payload_bit_is_a_crc_error() {
+		let mut bytes = frame(1, 7, &[0x10, 0x20]);
+		bytes[4] ^= 0x04;
+
+		assert_eq!(decode_all(&bytes), [Err(DecodeError::Crc)]);
+	}
+
+	#[test]
+	fn dropped_byte_costs_only_its_frame() {
+		let mut bytes = frame(1, 1, &[1, 2, 3, 4]);
+		bytes.remove(5);
+		bytes.extend(frame(1, 2, &[5, 6]));
+		bytes.extend(frame(1, 3, &[7]));
+
+		assert_eq!(
+			frames(&decode_all(&bytes)),
+			[(1, 2, vec![5, 6]), (1, 3, vec![7])]
+		);
+	}
+
+	#[test]
+	fn resyncs_after_garbage_and_false_sync_bytes() {
+		// Noise, including a sync byte with a plausible length...
+		let mut bytes = vec![0x00, 0xFF, SYNC, 3, 0x42, SYNC];
+		// ...then a frame with sync bytes in its payload, corrupted so
+		// that the decoder has to rescan through them...
+		let mut corrupted = frame(1, 1, &[SYNC, 2, 1, 0, SYNC, SYNC]);
+		let last = corrupted.len() - 1;
+		corrupted[last] ^= 0xFF;
+		bytes.extend(corrupted);
+		// ...and the frames after it still get through.
+		bytes.extend(frame(1, 2, &[SYNC]));
+		bytes.extend(frame(1, 3, &[SYNC, SYNC]));
+
+		assert_eq!(
+			frames(&decode_all(&bytes)),
+			[(1, 2, vec![SYNC]), (1, 3, vec![SYNC, SYNC])]
+		);
+	}
+
+	#[test]
+	fn oversized_length_is_malformed() {
+		let mut bytes = vec![SYNC, MAX_PAYLOAD as u8 + 1, 1, 1];
+		bytes.extend(frame(1, 2, &[9]));
+
+		let decoded = decode_all(&bytes);
+		assert_eq!(decoded[0], Err(DecodeError::Malformed));
+		assert_eq!(frames(&decoded), [(1, 2, vec![9])]);
+	}
+
+	#[test]
+	fn unknown_type_is_malformed() {
+		let mut bytes = frame(9, 1, &[]);
+		bytes.extend(frame(1, 2, &[]));
+
+		assert_eq!(
+			decode_all(&bytes),
+			[Err(DecodeError::Malformed), Ok((1, 2, vec![]))]
+		);
+	}
+
+	#[test]
+	fn whole_frames_stop_before_a_partial_one() {
+		let mut bytes = frame(1, 0, &[1, 2, 3]);
+		bytes.extend(frame(1, 1, &[]));
+		let whole = bytes.len();
+		bytes.extend(&frame(1, 2, &[4, 5])[..4]);
+
+		assert_eq!(whole_frames_len(&bytes), whole);
+		assert_eq!(whole_frames_len(&bytes[..whole + 1]), whole);
+		assert_eq!(whole_frames_len(&bytes[..whole - 1]), 9);
+		assert_eq!(whole_frames_len(&[]), 0);
+	}
+
+	#[test]
+	fn garbage_counts_as_whole_frames() {
+		let mut bytes = frame(1, 0, &[1]);
+		bytes.extend([0x00, SYNC]);
+		assert_eq!(whole_frames_len(&bytes), bytes.len());
+
+		assert_eq!(whole_frames_len(&[SYNC, MAX_PAYLOAD as u8 + 1]), 2);
+	}
+}
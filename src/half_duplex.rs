@@ -0,0 +1,188 @@
+//! Single-wire transport for the split link.
+//!
+//! Both halves share one open-drain line on `PIN_1`, which idles high
+//! through the pull-up and is only ever driven low. Only the half holding
+//! the turn may transmit; it hands the turn over by ending its burst with
+//! a turn frame. The left half initiates: it offers the turn at least every
+//! `POLL_INTERVAL`, and reclaims it if the right half doesn't hand it back
+//! within `TURN_TIMEOUT`. The right half only ever speaks when spoken to.
+//!
+//! Frames are exchanged through the `TX` and `RX` pipes, so the link in
+//! `uart` is the same for both transports.
+
+use embassy_futures::join::join;
+use embassy_rp::{
+	clocks::clk_sys_freq,
+	gpio::{Level, Pull},
+	peripherals::{PIN_1, PIO0},
+	pio::{Common, Config, Direction, FifoJoin, ShiftConfig, ShiftDirection, StateMachine},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe, signal::Signal};
+use embassy_time::{Duration, with_timeout};
+use fixed::{traits::ToFixed, types::U56F8};
+use portable_atomic::{AtomicUsize, Ordering};
+
+use crate::{BoardSide, framing, uart};
+
+/// Frames waiting to go out on our next turn.
+pub static TX: Pipe<CriticalSectionRawMutex, 256> = Pipe::new();
+/// Bytes received from the other half, without the echo of our own.
+pub static RX: Pipe<CriticalSectionRawMutex, 256> = Pipe::new();
+
+/// Raised by the frame reader when the other half hands us the turn.
+pub static TURN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// How often the left half offers the turn when it has nothing to send.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// How long the left half waits for the turn to come back before
+/// assuming it was lost. Must exceed the longest burst.
+const TURN_TIMEOUT: Duration = Duration::from_millis(30);
+/// The most bytes sent in a single turn (about 11ms at 115200 baud).
+/// Always fits at least one whole frame.
+const MAX_BURST: usize = 128;
+
+/// The number of bytes we've sent that have yet to be heard back.
+static ECHO: AtomicUsize = AtomicUsize::new(0);
+
+pub async fn run(
+	common: &mut Common<'_, PIO0>,
+	mut sm_tx: StateMachine<'_, PIO0, 0>,
+	mut sm_rx: StateMachine<'_, PIO0, 1>,
+	pin: PIN_1,
+	baud: u32,
+	side: BoardSide,
+) -> ! {
+	let mut pin = common.make_pio_pin(pin);
+	pin.set_pull(Pull::Up);
+
+	// 8n1, like `PioUartTx`, but shifting inverted data into the pin
+	// direction so that the line is released (rather than driven) high.
+	let tx_program = pio_proc::pio_asm!(
+		r#"
+			.side_set 1 opt pindirs
+			pull            side 0 [7]
+			set x, 7        side 1 [7]
+		bitloop:
+			out pindirs, 1         [6]
+			jmp x-- bitloop
+		"#
+	);
+
+	let rx_program = pio_proc::pio_asm!(
+		r#"
+		start:
+			wait 0 pin 0
+			set x, 7        [10]
+		bitloop:
+			in pins, 1
+			jmp x-- bitloop [6]
+			jmp pin good_stop
+			wait 1 pin 0
+			jmp start
+		good_stop:
+			push
+		"#
+	);
+
+	let divider = (U56F8::from_num(clk_sys_freq()) / U56F8::from_num(8 * baud)).to_fixed();
+
+	sm_tx.set_pins(Level::Low, &[&pin]);
+	sm_tx.set_pin_dirs(Direction::In, &[&pin]);
+
+	let mut cfg = Config::default();
+	cfg.use_program(&common.load_program(&tx_program.program), &[&pin]);
+	cfg.set_out_pins(&[&pin]);
+	cfg.shift_out = ShiftConfig {
+		auto_fill: false,
+		threshold: 32,
+		direction: ShiftDirection::Right,
+	};
+	cfg.fifo_join = FifoJoin::TxOnly;
+	cfg.clock_divider = divider;
+	sm_tx.set_config(&cfg);
+	sm_tx.set_enable(true);
+
+	let mut cfg = Config::default();
+	cfg.use_program(&common.load_program(&rx_program.program), &[]);
+	cfg.set_in_pins(&[&pin]);
+	cfg.set_jmp_pin(&pin);
+	cfg.shift_in = ShiftConfig {
+		auto_fill: false,
+		threshold: 32,
+		direction: ShiftDirection::Right,
+	};
+	cfg.fifo_join = FifoJoin::RxOnly;
+	cfg.clock_divider = divider;
+	sm_rx.set_config(&cfg);
+	sm_rx.set_enable(true);
+
+	let rx_fut = async {
+		loop {
+			let byte = (sm_rx.rx().wait_pull().await >> 24) as u8;
+
+			if ECHO.load(Ordering::Relaxed) > 0 {
+				ECHO.fetch_sub(1, Ordering::Relaxed);
+				continue;
+			}
+
+			RX.write_all(&[byte]).await;
+		}
+	};
+
+	let tx_fut = async {
+		let mut buf = [0; MAX_BURST];
+		// The start of a frame that didn't fit in the last burst.
+		let mut carried = 0;
+		let mut frame = [0; framing::MAX_FRAME_SIZE];
+		let mut have_turn = side == BoardSide::Left;
+
+		loop {
+			if !have_turn {
+				match side {
+					BoardSide::Left => {
+						// Reclaim the turn on timeout; it was lost somewhere.
+						with_timeout(TURN_TIMEOUT, TURN.wait()).await.ok();
+					}
+					BoardSide::Right => TURN.wait().await,
+				}
+
+				// Anything still unheard of our last burst was lost to noise.
+				ECHO.store(0, Ordering::Relaxed);
+			}
+
+			let read = match side {
+				BoardSide::Left if carried == 0 && TX.is_empty() => {
+					with_timeout(POLL_INTERVAL, TX.read(&mut buf))
+						.await
+						.unwrap_or(0)
+				}
+				_ => TX.try_read(&mut buf[carried..]).unwrap_or(0),
+			};
+
+			let mut len = carried + read;
+			if len < MAX_BURST {
+				len += TX.try_read(&mut buf[len..]).unwrap_or(0);
+			}
+
+			// A frame cut short would sit half-sent through the turnaround,
+			// so it waits for the next turn instead.
+			let burst = framing::whole_frames_len(&buf[..len]);
+
+			TURN.reset();
+
+			let turn_len = uart::turn_frame(&mut frame);
+			for &byte in buf[..burst].iter().chain(&frame[..turn_len]) {
+				ECHO.fetch_add(1, Ordering::Relaxed);
+				sm_tx.tx().wait_push(!byte as u32).await;
+			}
+
+			buf.copy_within(burst..len, 0);
+			carried = len - burst;
+			have_turn = false;
+		}
+	};
+
+	join(rx_fut, tx_fut).await;
+
+	unreachable!();
+}
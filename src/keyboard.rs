@@ -0,0 +1,933 @@
+//! The main loop that turns key and encoder events from both halves into
+//! USB reports.
+
+use core::{cell::RefCell, sync::atomic::Ordering};
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either4, select, select4};
+use embassy_rp::flash::Flash;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use static_cell::StaticCell;
+
+#[cfg(feature = "split-update")]
+use crate::update;
+use crate::{
+	BoardSide, animation,
+	encoder::{self, EncoderConfig},
+	keymap,
+	keyprobe::{self, KeyprobeConfig, keyprobe_task},
+	led::{self, LedConfig, led_task},
+	oled, settings, state, uart, usb,
+};
+
+/// How long the super-tab modifier stays held after the last encoder detent.
+const SUPER_TAB_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How often the full matrix state is sent to the other half.
+const MATRIX_RESYNC_INTERVAL: Duration = Duration::from_millis(1000);
+/// How long the master holds on to key events, so that one from the other
+/// half that happened earlier but arrives later still goes out first.
+const REORDER_WINDOW: Duration = Duration::from_millis(3);
+
+#[derive(Clone, Copy)]
+enum EncoderAction {
+	/// Sends the first consumer usage when turned clockwise,
+	/// and the second when turned counter-clockwise.
+	Consumer(u16, u16),
+	/// Holds the given modifier bits and taps Tab (or Shift+Tab when
+	/// turned counter-clockwise) on every detent, releasing the modifier
+	/// once the encoder has been idle for `SUPER_TAB_TIMEOUT`.
+	SuperTab(u8),
+	/// Steps the OLED contrast up when turned clockwise, down otherwise.
+	Contrast,
+}
+
+/// Indexed by the layer bits rather than the layer, so that holding both
+/// layer keys gets its own row, then by encoder (left, right).
+#[rustfmt::skip]
+static ENCODER_MAP: [[EncoderAction; 2]; 4] = [
+	[
+		// Next/previous track, volume up/down
+		EncoderAction::Consumer(0xB5, 0xB6), EncoderAction::Consumer(0xE9, 0xEA),
+	],
+	[
+		// Alt+Tab, volume up/down
+		EncoderAction::SuperTab(1 << 2), EncoderAction::Consumer(0xE9, 0xEA),
+	],
+	[
+		// GUI+Tab, volume up/down
+		EncoderAction::SuperTab(1 << 3), EncoderAction::Consumer(0xE9, 0xEA),
+	],
+	[
+		// GUI+Tab, OLED contrast
+		EncoderAction::SuperTab(1 << 3), EncoderAction::Contrast,
+	],
+];
+
+/// Maps the (otherwise reserved) keycodes `0xA5..=0xA7` used in the keymap
+/// to System Power Down, System Sleep and System Wake Up respectively.
+const SYSTEM_USAGES: [u8; 3] = [0x81, 0x82, 0x83];
+/// Another reserved keycode, which toggles the split link counters on both OLEDs.
+const DEBUG_SCREEN_KEY: u8 = 0xA8;
+/// More reserved keycodes, for the OLED settings.
+const CONTRAST_UP_KEY: u8 = 0xA9;
+const CONTRAST_DOWN_KEY: u8 = 0xAA;
+const INVERT_KEY: u8 = 0xAB;
+const ROTATE_KEY: u8 = 0xAC;
+
+pub async fn run_alchemist(spawner: Spawner, side: BoardSide) -> ! {
+	let p = embassy_rp::init(Default::default());
+
+	static FLASH: StaticCell<settings::SharedFlash> = StaticCell::new();
+	let flash = FLASH.init(Mutex::new(RefCell::new(Flash::new_blocking(p.FLASH))));
+
+	// Before anything shows the display settings.
+	settings::load(flash);
+	spawner.spawn(settings::settings_task(flash)).unwrap();
+
+	let led_config = LedConfig { pin_17: p.PIN_17 };
+
+	spawner.spawn(led_task(led_config)).unwrap();
+
+	let keyprobe_config = KeyprobeConfig {
+		pin_27: p.PIN_27,
+		pin_26: p.PIN_26,
+		pin_22: p.PIN_22,
+		pin_20: p.PIN_20,
+		pin_23: p.PIN_23,
+		pin_21: p.PIN_21,
+		pin_5:  p.PIN_5,
+		pin_6:  p.PIN_6,
+		pin_7:  p.PIN_7,
+		pin_8:  p.PIN_8,
+		pin_9:  p.PIN_9,
+	};
+
+	spawner.spawn(keyprobe_task(keyprobe_config)).unwrap();
+
+	let encoder_config = EncoderConfig {
+		pin_29: p.PIN_29,
+		pin_28: p.PIN_28,
+	};
+
+	spawner
+		.spawn(encoder::encoder_task(encoder_config))
+		.unwrap();
+
+	let usb_config = usb::UsbConfig { usb_dev: p.USB };
+
+	spawner.spawn(usb::usb_task(usb_config)).unwrap();
+
+	let oled_config = oled::OledConfig {
+		i2c1:  p.I2C1,
+		pin_3: p.PIN_3,
+		pin_2: p.PIN_2,
+	};
+
+	spawner.spawn(oled::oled_task(oled_config)).unwrap();
+
+	let uart_config = uart::UartConfig {
+		pin_1: p.PIN_1,
+		pin_4: p.PIN_4,
+		side,
+		pio0: p.PIO0,
+	};
+
+	spawner.spawn(uart::uart_task(uart_config)).unwrap();
+
+	#[cfg(feature = "split-update")]
+	spawner
+		.spawn(update::update_task(update::UpdateConfig { flash }))
+		.unwrap();
+
+	let mut key_buffer: [u8; 6] = [0; 6];
+	let mut layer_mask = 0;
+	let mut modifiers = 0;
+	let mut super_tab = SuperTab::new();
+	// Keys held down on this half, and on the other half as
+	// reported over the split link.
+	let mut local_keys: u32 = 0;
+	let mut remote_keys: u32 = 0;
+	let mut next_resync = Instant::now() + MATRIX_RESYNC_INTERVAL;
+	let mut remote_configured = false;
+	let mut was_master = false;
+	let mut published_state: Option<state::SharedState> = None;
+	let mut pending: Vec<PendingKey, 16> = Vec::new();
+
+	let right_side = side == BoardSide::Right;
+
+	loop {
+		let mut deadline = match super_tab.deadline {
+			Some(deadline) => deadline.min(next_resync),
+			None => next_resync,
+		};
+		if let Some(key) = pending.first() {
+			deadline = deadline.min(key.due);
+		}
+
+		let event = select4(
+			keyprobe::EVENTS.receive(),
+			uart::LINK.incoming.receive(),
+			encoder::EVENTS.receive(),
+			select(Timer::at(deadline), state::DIRTY.wait()),
+		)
+		.await;
+
+		// Only the half with an enumerated USB host turns key events into
+		// reports; the other one just forwards them. If both are plugged
+		// in, the left half wins.
+		let master = usb::is_configured() && !(remote_configured && right_side);
+
+		if master != was_master {
+			was_master = master;
+			published_state = None;
+			state::MASTER.store(master, Ordering::Relaxed);
+
+			key_buffer = [0; 6];
+			layer_mask = 0;
+			modifiers = 0;
+			super_tab = SuperTab::new();
+			// Covered by replaying `local_keys` and `remote_keys` below.
+			pending.clear();
+
+			// Don't leave anything stuck on a host we're no longer driving.
+			usb::OUTGOING
+				.try_send(usb::Event::Update(key_buffer, modifiers))
+				.ok();
+
+			if master {
+				// Catch the new host up on whatever is already held down.
+				reconcile_keys(
+					&mut key_buffer,
+					&mut layer_mask,
+					&mut modifiers,
+					&mut 0,
+					local_keys,
+					true,
+					right_side,
+				);
+				reconcile_keys(
+					&mut key_buffer,
+					&mut layer_mask,
+					&mut modifiers,
+					&mut 0,
+					remote_keys,
+					false,
+					right_side,
+				);
+			}
+		}
+
+		match event {
+			Either4::First(keyprobe::Event::Down(x, y, at)) => {
+				keymap::set_key_bit(&mut local_keys, x, y, true);
+				if master {
+					queue_key(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						PendingKey {
+							due: at + REORDER_WINDOW,
+							x,
+							y,
+							from_us: true,
+							down: true,
+						},
+						right_side,
+					);
+				}
+				uart::LINK.try_send(uart::Packet::Down(x, y, uart::timestamp(at)));
+				usb::wake_host();
+				oled::spawn_star();
+				animation::trigger(animation::Trigger::KeyPress);
+				oled::wake();
+			}
+			Either4::First(keyprobe::Event::Up(x, y, at)) => {
+				keymap::set_key_bit(&mut local_keys, x, y, false);
+				if master {
+					queue_key(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						PendingKey {
+							due: at + REORDER_WINDOW,
+							x,
+							y,
+							from_us: true,
+							down: false,
+						},
+						right_side,
+					);
+				}
+				uart::LINK.try_send(uart::Packet::Up(x, y, uart::timestamp(at)));
+				oled::spawn_star();
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::Down(x, y, at))) => {
+				keymap::set_key_bit(&mut remote_keys, x, y, true);
+				if master {
+					queue_key(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						PendingKey {
+							due: uart::LINK.remote_instant(at) + REORDER_WINDOW,
+							x,
+							y,
+							from_us: false,
+							down: true,
+						},
+						right_side,
+					);
+				}
+				led::LED_STATE.signal(led::LedState::On);
+				usb::wake_host();
+				oled::spawn_star();
+				animation::trigger(animation::Trigger::KeyPress);
+				oled::wake();
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::Up(x, y, at))) => {
+				keymap::set_key_bit(&mut remote_keys, x, y, false);
+				if master {
+					queue_key(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						PendingKey {
+							due: uart::LINK.remote_instant(at) + REORDER_WINDOW,
+							x,
+							y,
+							from_us: false,
+							down: false,
+						},
+						right_side,
+					);
+				}
+				led::LED_STATE.signal(led::LedState::Off);
+				oled::spawn_star();
+			}
+			Either4::Third(event) => {
+				// The right encoder is mounted mirrored, so flip it in order
+				// for both encoders to report the same logical direction.
+				let cw = matches!(event, encoder::Event::Cw) != right_side;
+
+				if master {
+					// Anything still pending happened before the turn.
+					dispatch_pending(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						Instant::MAX,
+						right_side,
+					);
+					dispatch_encoder(
+						&key_buffer,
+						layer_mask,
+						&mut modifiers,
+						&mut super_tab,
+						right_side,
+						cw,
+					);
+				}
+
+				let packet = if cw {
+					uart::Packet::EncoderCw
+				} else {
+					uart::Packet::EncoderCcw
+				};
+				uart::LINK.try_send(packet);
+				oled::wake();
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::EncoderCw)) => {
+				if master {
+					dispatch_pending(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						Instant::MAX,
+						right_side,
+					);
+					dispatch_encoder(
+						&key_buffer,
+						layer_mask,
+						&mut modifiers,
+						&mut super_tab,
+						!right_side,
+						true,
+					);
+				}
+				oled::wake();
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::EncoderCcw)) => {
+				if master {
+					dispatch_pending(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						Instant::MAX,
+						right_side,
+					);
+					dispatch_encoder(
+						&key_buffer,
+						layer_mask,
+						&mut modifiers,
+						&mut super_tab,
+						!right_side,
+						false,
+					);
+				}
+				oled::wake();
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::UsbSuspend(suspended))) => {
+				usb::set_suspended(suspended);
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::Matrix(keys))) => {
+				if master {
+					dispatch_pending(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						Instant::MAX,
+						right_side,
+					);
+					reconcile_keys(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut remote_keys,
+						keys,
+						false,
+						right_side,
+					);
+				} else {
+					remote_keys = keys;
+				}
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::UsbConfigured(configured))) => {
+				remote_configured = configured;
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::Hello { version, build })) => {
+				let mismatch = version != uart::PROTOCOL_VERSION || build != uart::BUILD_HASH;
+				uart::FIRMWARE_MISMATCH.store(mismatch, Ordering::Relaxed);
+				led::set_warning(mismatch);
+			}
+			Either4::Second(uart::Event::Packet(uart::Packet::State(shared))) => {
+				if !master {
+					state::STATE.signal(shared);
+				}
+			}
+			// These go to `uart::LINK.updates` instead.
+			Either4::Second(uart::Event::Packet(
+				uart::Packet::UpdateBegin { .. }
+				| uart::Packet::UpdateChunk { .. }
+				| uart::Packet::UpdateAck(_)
+				| uart::Packet::UpdateDone(_),
+			)) => {}
+			Either4::Second(uart::Event::LinkUp) => {
+				// Make sure the other half gets the current state.
+				published_state = None;
+				uart::LINK.try_send(uart::Packet::Hello {
+					version: uart::PROTOCOL_VERSION,
+					build:   uart::BUILD_HASH,
+				});
+				uart::LINK.try_send(uart::Packet::UsbConfigured(usb::is_configured()));
+				uart::LINK.try_send(uart::Packet::Matrix(local_keys));
+				next_resync = Instant::now() + MATRIX_RESYNC_INTERVAL;
+			}
+			Either4::Second(uart::Event::LinkDown) => {
+				remote_configured = false;
+				uart::FIRMWARE_MISMATCH.store(false, Ordering::Relaxed);
+				led::set_warning(false);
+
+				// Nothing the other half is holding will ever be released now.
+				if master {
+					dispatch_pending(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut pending,
+						Instant::MAX,
+						right_side,
+					);
+					reconcile_keys(
+						&mut key_buffer,
+						&mut layer_mask,
+						&mut modifiers,
+						&mut remote_keys,
+						0,
+						false,
+						right_side,
+					);
+
+					if super_tab.right_encoder != right_side {
+						super_tab.release(&key_buffer, &mut modifiers);
+					}
+				} else {
+					remote_keys = 0;
+				}
+
+				led::LED_STATE.signal(led::LedState::Off);
+			}
+			Either4::Fourth(_) => {
+				let now = Instant::now();
+
+				if super_tab.deadline.is_some_and(|deadline| deadline <= now) {
+					super_tab.release(&key_buffer, &mut modifiers);
+				}
+
+				if next_resync <= now {
+					if uart::LINK.is_up() {
+						uart::LINK.try_send(uart::Packet::Matrix(local_keys));
+					}
+					next_resync = now + MATRIX_RESYNC_INTERVAL;
+				}
+			}
+		}
+
+		if master {
+			dispatch_pending(
+				&mut key_buffer,
+				&mut layer_mask,
+				&mut modifiers,
+				&mut pending,
+				Instant::now(),
+				right_side,
+			);
+
+			let shared = state::SharedState {
+				layers: layer_mask,
+				modifiers,
+				host_leds: usb::host_leds(),
+				suspended: usb::SUSPENDED.load(Ordering::Relaxed),
+				display: settings::display(),
+				debug_screen: oled::debug_screen(),
+			};
+
+			if published_state != Some(shared) {
+				published_state = Some(shared);
+				state::STATE.signal(shared);
+				uart::LINK.try_send(uart::Packet::State(shared));
+			}
+		}
+	}
+}
+
+/// A key event held back by the master for `REORDER_WINDOW`.
+struct PendingKey {
+	/// When the event happened (on our clock), plus `REORDER_WINDOW`.
+	due:     Instant,
+	x:       u8,
+	y:       u8,
+	from_us: bool,
+	down:    bool,
+}
+
+/// Adds `key` to `pending`, which is kept sorted by when the keys are due.
+fn queue_key<const N: usize>(
+	key_buffer: &mut [u8; 6],
+	layers: &mut u8,
+	modifiers: &mut u8,
+	pending: &mut Vec<PendingKey, N>,
+	key: PendingKey,
+	right_side: bool,
+) {
+	if pending.is_full() {
+		dispatch_pending(
+			key_buffer,
+			layers,
+			modifiers,
+			pending,
+			Instant::MAX,
+			right_side,
+		);
+	}
+
+	let idx = pending
+		.iter()
+		.position(|other| other.due > key.due)
+		.unwrap_or(pending.len());
+
+	pending.insert(idx, key).ok();
+}
+
+/// Dispatches, in order, the pending key events due by `now`.
+fn dispatch_pending<const N: usize>(
+	key_buffer: &mut [u8; 6],
+	layers: &mut u8,
+	modifiers: &mut u8,
+	pending: &mut Vec<PendingKey, N>,
+	now: Instant,
+	right_side: bool,
+) {
+	let due = pending.iter().take_while(|key| key.due <= now).count();
+
+	for key in pending.iter().take(due) {
+		let column = keymap::column(key.x, key.from_us, right_side);
+		dispatch_key(key_buffer, layers, modifiers, column, key.y, key.down);
+	}
+
+	for _ in 0..due {
+		pending.remove(0);
+	}
+}
+
+struct SuperTab {
+	/// The modifier bits pressed by us (and not by a physical key).
+	held:          u8,
+	deadline:      Option<Instant>,
+	/// Which encoder last drove the super-tab.
+	right_encoder: bool,
+}
+
+impl SuperTab {
+	fn new() -> Self {
+		SuperTab {
+			held:          0,
+			deadline:      None,
+			right_encoder: false,
+		}
+	}
+
+	fn tab(
+		&mut self,
+		key_buffer: &[u8; 6],
+		modifiers: &mut u8,
+		modifier: u8,
+		right_encoder: bool,
+		forward: bool,
+	) {
+		self.right_encoder = right_encoder;
+
+		if self.deadline.is_none() {
+			self.held = modifier & !*modifiers;
+			*modifiers |= modifier;
+		}
+
+		self.deadline = Some(Instant::now() + SUPER_TAB_TIMEOUT);
+
+		let mut tab_buffer = *key_buffer;
+		let mut tab_modifiers = *modifiers;
+		add_keycode(&mut tab_buffer, &mut tab_modifiers, 0x2B);
+		if !forward {
+			// Left shift
+			tab_modifiers |= 1 << 1;
+		}
+
+		usb::OUTGOING
+			.try_send(usb::Event::Update(tab_buffer, tab_modifiers))
+			.ok();
+		usb::OUTGOING
+			.try_send(usb::Event::Update(*key_buffer, *modifiers))
+			.ok();
+	}
+
+	fn release(&mut self, key_buffer: &[u8; 6], modifiers: &mut u8) {
+		self.deadline = None;
+
+		if self.held != 0 {
+			*modifiers &= !self.held;
+			self.held = 0;
+			usb::OUTGOING
+				.try_send(usb::Event::Update(*key_buffer, *modifiers))
+				.ok();
+		}
+	}
+}
+
+fn dispatch_encoder(
+	key_buffer: &[u8; 6],
+	layers: u8,
+	modifiers: &mut u8,
+	super_tab: &mut SuperTab,
+	right_encoder: bool,
+	cw: bool,
+) {
+	match ENCODER_MAP[(layers & 0b11) as usize][right_encoder as usize] {
+		EncoderAction::Consumer(cw_usage, ccw_usage) => {
+			let usage = if cw { cw_usage } else { ccw_usage };
+			usb::OUTGOING.try_send(usb::Event::Consumer(usage)).ok();
+		}
+		EncoderAction::SuperTab(modifier) => {
+			super_tab.tab(key_buffer, modifiers, modifier, right_encoder, cw);
+		}
+		EncoderAction::Contrast => settings::step_contrast(cw),
+	}
+}
+
+/// Synthesizes the presses and releases needed to bring one
+/// half's keys from `current` to `keys`.
+fn reconcile_keys(
+	key_buffer: &mut [u8; 6],
+	layers: &mut u8,
+	modifiers: &mut u8,
+	current: &mut u32,
+	keys: u32,
+	from_us: bool,
+	right_side: bool,
+) {
+	keymap::reconcile(current, keys, from_us, right_side, |column, y, down| {
+		dispatch_key(key_buffer, layers, modifiers, column, y, down);
+	});
+}
+
+fn dispatch_key(
+	key_buffer: &mut [u8; 6],
+	layers: &mut u8,
+	modifiers: &mut u8,
+	column: u8,
+	y: u8,
+	down: bool,
+) {
+	if update_key_data(key_buffer, layers, modifiers, column, y, down) {
+		usb::OUTGOING
+			.try_send(usb::Event::Update(*key_buffer, *modifiers))
+			.ok();
+	}
+}
+
+fn update_key_data(
+	key_buffer: &mut [u8; 6],
+	layers: &mut u8,
+	modifiers: &mut u8,
+	x: u8,
+	y: u8,
+	down: bool,
+) -> bool {
+	if x >= 12 || y >= 5 {
+		return false;
+	}
+
+	if x == 9 && y == 4 {
+		if down {
+			*layers |= 1 << 0;
+		} else {
+			*layers &= !(1 << 0);
+		}
+		return false;
+	}
+
+	if x == 11 && y == 4 {
+		if down {
+			*layers |= 1 << 1;
+		} else {
+			*layers &= !(1 << 1);
+		}
+		return false;
+	}
+
+	// A bit of a hack - we handle media keys here, directly.
+	if x == 5 && y == 4 {
+		if down {
+			usb::OUTGOING.try_send(usb::Event::ConsumerDown(0xCD)).ok();
+			led::LED_STATE.signal(led::LedState::BlinkFast);
+		} else {
+			usb::OUTGOING.try_send(usb::Event::ConsumerUp(0xCD)).ok();
+			led::LED_STATE.signal(led::LedState::Off);
+		}
+		return false;
+	}
+	if x == 6 && y == 4 {
+		if down {
+			usb::OUTGOING.try_send(usb::Event::ConsumerDown(0xE2)).ok();
+			led::LED_STATE.signal(led::LedState::BlinkSlow);
+		} else {
+			usb::OUTGOING.try_send(usb::Event::ConsumerUp(0xE2)).ok();
+			led::LED_STATE.signal(led::LedState::Off);
+		}
+		return false;
+	}
+
+	let key = keymap::keycode(*layers, x, y);
+
+	if key == 0 {
+		// Not mapped; ignore.
+		return false;
+	}
+
+	if down {
+		add_keycode(key_buffer, modifiers, key)
+	} else {
+		// Kind of weird, but we want to un-press any keys that are
+		// mapped to the same key code on that key on any layer.
+		let mut update = false;
+
+		for key in keymap::keycodes(x, y) {
+			update = update || remove_keycode(key_buffer, modifiers, key);
+		}
+
+		update
+	}
+}
+
+fn add_keycode(key_buffer: &mut [u8; 6], modifiers: &mut u8, code: u8) -> bool {
+	match code {
+		0 => false,
+		// System Power Down, Sleep, Wake Up
+		0xA5..=0xA7 => {
+			let usage_id = SYSTEM_USAGES[(code - 0xA5) as usize];
+			usb::OUTGOING
+				.try_send(usb::Event::SystemDown(usage_id))
+				.ok();
+			false
+		}
+		DEBUG_SCREEN_KEY => {
+			oled::toggle_debug_screen();
+			false
+		}
+		CONTRAST_UP_KEY | CONTRAST_DOWN_KEY => {
+			settings::step_contrast(code == CONTRAST_UP_KEY);
+			false
+		}
+		INVERT_KEY => {
+			settings::toggle_inverted();
+			false
+		}
+		ROTATE_KEY => {
+			settings::toggle_rotated();
+			false
+		}
+		// Right Shift
+		0xC6 | 0xE5 => {
+			let r = ((*modifiers) & (1 << 5)) == 0;
+			*modifiers |= 1 << 5;
+			r
+		}
+		// Left shift
+		0xE1 | 0xC5 => {
+			let r = ((*modifiers) & (1 << 1)) == 0;
+			*modifiers |= 1 << 1;
+			r
+		}
+		// Right Control
+		0xE4 => {
+			let r = ((*modifiers) & (1 << 4)) == 0;
+			*modifiers |= 1 << 4;
+			r
+		}
+		// Left Control
+		0xE0 => {
+			let r = ((*modifiers) & (1 << 0)) == 0;
+			*modifiers |= 1 << 0;
+			r
+		}
+		// Right Alt
+		0xE6 => {
+			let r = ((*modifiers) & (1 << 6)) == 0;
+			*modifiers |= 1 << 6;
+			r
+		}
+		// Left Alt
+		0xE2 => {
+			let r = ((*modifiers) & (1 << 2)) == 0;
+			*modifiers |= 1 << 2;
+			r
+		}
+		// Right GUI
+		0xE7 => {
+			let r = ((*modifiers) & (1 << 7)) == 0;
+			*modifiers |= 1 << 7;
+			r
+		}
+		// Left GUI
+		0xE3 => {
+			let r = ((*modifiers) & (1 << 3)) == 0;
+			*modifiers |= 1 << 3;
+			r
+		}
+		_ => {
+			for i in 0..6 {
+				if key_buffer[i] == code {
+					return false;
+				}
+			}
+
+			for i in 0..6 {
+				if key_buffer[i] == 0 {
+					key_buffer[i] = code;
+					return true;
+				}
+			}
+
+			false
+		}
+	}
+}
+
+fn remove_keycode(key_buffer: &mut [u8; 6], modifiers: &mut u8, code: u8) -> bool {
+	match code {
+		0 => false,
+		// System Power Down, Sleep, Wake Up
+		0xA5..=0xA7 => {
+			let usage_id = SYSTEM_USAGES[(code - 0xA5) as usize];
+			usb::OUTGOING.try_send(usb::Event::SystemUp(usage_id)).ok();
+			false
+		}
+		DEBUG_SCREEN_KEY | CONTRAST_UP_KEY | CONTRAST_DOWN_KEY | INVERT_KEY | ROTATE_KEY => false,
+		// Right Shift
+		0xC6 | 0xE5 => {
+			let r = ((*modifiers) & (1 << 5)) != 0;
+			*modifiers &= !(1 << 5);
+			r
+		}
+		// Left shift
+		0xE1 | 0xC5 => {
+			let r = ((*modifiers) & (1 << 1)) != 0;
+			*modifiers &= !(1 << 1);
+			r
+		}
+		// Right Control
+		0xE4 => {
+			let r = ((*modifiers) & (1 << 4)) != 0;
+			*modifiers &= !(1 << 4);
+			r
+		}
+		// Left Control
+		0xE0 => {
+			let r = ((*modifiers) & (1 << 0)) != 0;
+			*modifiers &= !(1 << 0);
+			r
+		}
+		// Right Alt
+		0xE6 => {
+			let r = ((*modifiers) & (1 << 6)) != 0;
+			*modifiers &= !(1 << 6);
+			r
+		}
+		// Left Alt
+		0xE2 => {
+			let r = ((*modifiers) & (1 << 2)) != 0;
+			*modifiers &= !(1 << 2);
+			r
+		}
+		// Right GUI
+		0xE7 => {
+			let r = ((*modifiers) & (1 << 7)) != 0;
+			*modifiers &= !(1 << 7);
+			r
+		}
+		// Left GUI
+		0xE3 => {
+			let r = ((*modifiers) & (1 << 3)) != 0;
+			*modifiers &= !(1 << 3);
+			r
+		}
+		_ => {
+			let mut update = false;
+			for i in 0..6 {
+				if key_buffer[i] == code {
+					key_buffer[i] = 0;
+					update = true;
+				}
+			}
+			update
+		}
+	}
+}
@@ -0,0 +1,190 @@
+//! The keymap, and which of its keys each half's matrix positions map to.
+
+#[rustfmt::skip]
+static KEYMAP: [[[u8; 12]; 5]; 3] = [
+	[
+		[0x29, 0x1E, 0x1F, 0x20, 0x21, 0x22,    0x23, 0x24, 0x25, 0x26, 0x27, 0x2A],
+		[0x2B, 0x14, 0x1A, 0x08, 0x15, 0x17,    0x1C, 0x18, 0x0C, 0x12, 0x13, 0x2E],
+		[0xE0, 0x04, 0x16, 0x07, 0x09, 0x0A,    0x0B, 0x0D, 0x0E, 0x0F, 0x33, 0x34],
+		[0xE1, 0x1D, 0x1B, 0x06, 0x19, 0x05,    0x11, 0x10, 0x36, 0x37, 0x38, 0x31],
+		[0x4A, 0x4D, 0xE2, 0x2C, 0xE3, 0x00,    0x00, 0x28, 0x2C, 0x00, 0x00, 0x00],
+	],
+	[
+		[0x35, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E,    0x3F, 0x40, 0x41, 0x42, 0x43, 0x2D],
+		[0x00, 0x44, 0x45, 0x68, 0x69, 0x6A,    0x6B, 0x6C, 0x6D, 0x6E, 0x2F, 0x30],
+		[0x00, 0xA6, 0xA7, 0x00, 0x00, 0xA5,    0x00, 0x00, 0x52, 0x00, 0x00, 0x00],
+		[0x00, 0x00, 0x00, 0x00, 0x00, 0x00,    0x00, 0x50, 0x51, 0x4F, 0x00, 0x00],
+		[0x4B, 0x4E, 0x00, 0x00, 0x00, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+	],
+	[
+		[0x00, 0xA8, 0x00, 0x00, 0x00, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x4C],
+		[0x00, 0x00, 0x00, 0x00, 0x00, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+		[0x00, 0xAA, 0xA9, 0xAB, 0xAC, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+		[0x00, 0x00, 0x00, 0x00, 0x00, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+		[0x00, 0x00, 0x00, 0x00, 0x00, 0x00,    0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+	]
+];
+
+const LAYER_LUT: [u8; 4] = [0, 1, 2, 2];
+
+/// Shown on the status screen, indexed like `KEYMAP`.
+static LAYER_NAMES: [&str; 3] = ["BASE", "FN", "SYS"];
+
+/// The name of the layer selected by the given layer bits.
+pub fn layer_name(layers: u8) -> &'static str {
+	LAYER_NAMES[LAYER_LUT[(layers & 0b11) as usize] as usize]
+}
+
+/// The keymap column of matrix column `x` on one of the halves.
+///
+/// The right half's matrix is mirrored, so its columns are flipped
+/// and placed after the left half's.
+pub fn column(x: u8, from_us: bool, right_side: bool) -> u8 {
+	if from_us == right_side {
+		(5 - x.min(5)) + 6
+	} else {
+		x
+	}
+}
+
+/// The keycode at `column`, `y` on the layer selected by the given layer
+/// bits, falling back to the base layer where that one has nothing.
+pub fn keycode(layers: u8, column: u8, y: u8) -> u8 {
+	let layer = LAYER_LUT[(layers & 0b11) as usize];
+	let key = KEYMAP[layer as usize][y as usize][column as usize];
+
+	if key == 0 {
+		KEYMAP[0][y as usize][column as usize]
+	} else {
+		key
+	}
+}
+
+/// The keycodes at `column`, `y` on every layer.
+pub fn keycodes(column: u8, y: u8) -> impl Iterator<Item = u8> {
+	KEYMAP
+		.iter()
+		.map(move |layer| layer[y as usize][column as usize])
+}
+
+pub fn key_bit(x: u8, y: u8) -> u32 {
+	1 << (x as u32 + y as u32 * 6)
+}
+
+pub fn set_key_bit(keys: &mut u32, x: u8, y: u8, down: bool) {
+	if x >= 6 || y >= 5 {
+		return;
+	}
+
+	if down {
+		*keys |= key_bit(x, y);
+	} else {
+		*keys &= !key_bit(x, y);
+	}
+}
+
+/// Calls `dispatch` with the keymap column, row and new state of every
+/// key that needs pressing or releasing to bring one half's keys from
+/// `current` to `keys`.
+pub fn reconcile(
+	current: &mut u32,
+	keys: u32,
+	from_us: bool,
+	right_side: bool,
+	mut dispatch: impl FnMut(u8, u8, bool),
+) {
+	let changed = *current ^ keys;
+
+	// Releases first, so that e.g. a missed layer key release
+	// doesn't affect the keys pressed alongside it.
+	for down in [false, true] {
+		for y in 0..5 {
+			for x in 0..6 {
+				let bit = key_bit(x, y);
+				if changed & bit != 0 && (keys & bit != 0) == down {
+					dispatch(column(x, from_us, right_side), y, down);
+				}
+			}
+		}
+	}
+
+	*current = keys;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn held(keys: &[(u8, u8)]) -> u32 {
+		let mut bits = 0;
+		for &(x, y) in keys {
+			set_key_bit(&mut bits, x, y, true);
+		}
+		bits
+	}
+
+	fn take_over(keys: u32, from_us: bool, right_side: bool) -> Vec<(u8, u8, bool)> {
+		let mut presses = Vec::new();
+		reconcile(&mut 0, keys, from_us, right_side, |column, y, down| {
+			presses.push((column, y, down))
+		});
+		presses
+	}
+
+	#[test]
+	fn takes_over_with_our_own_keys_held() {
+		// The right half's outermost key on the top letter row, and the
+		// key next to its thumb cluster (the first layer key).
+		let keys = held(&[(0, 1), (2, 4)]);
+
+		let presses = take_over(keys, true, true);
+		assert_eq!(presses, [(11, 1, true), (9, 4, true)]);
+		assert_eq!(keycode(0, 11, 1), 0x2E);
+
+		// The same keys on the left half, from there.
+		let presses = take_over(keys, true, false);
+		assert_eq!(presses, [(0, 1, true), (2, 4, true)]);
+		assert_eq!(keycode(0, 0, 1), 0x2B);
+		assert_eq!(keycode(0, 2, 4), 0xE2);
+	}
+
+	#[test]
+	fn takes_over_with_the_other_halfs_keys_held() {
+		let keys = held(&[(0, 1)]);
+
+		assert_eq!(take_over(keys, false, true), [(0, 1, true)]);
+		assert_eq!(take_over(keys, false, false), [(11, 1, true)]);
+	}
+
+	#[test]
+	fn releases_before_pressing() {
+		let mut current = held(&[(2, 4)]);
+		let mut presses = Vec::new();
+		reconcile(
+			&mut current,
+			held(&[(0, 1)]),
+			true,
+			true,
+			|column, y, down| presses.push((column, y, down)),
+		);
+
+		assert_eq!(presses, [(9, 4, false), (11, 1, true)]);
+		assert_eq!(current, held(&[(0, 1)]));
+	}
+
+	#[test]
+	fn falls_back_to_the_base_layer() {
+		assert_eq!(keycode(1, 0, 2), 0xE0);
+		assert_eq!(keycode(1, 8, 2), 0x52);
+		assert!(keycodes(8, 2).eq([0x0E, 0x52, 0x00]));
+	}
+
+	#[test]
+	fn system_keys_are_on_the_function_layer() {
+		assert_eq!(layer_name(0b01), "FN");
+		assert_eq!(
+			[1, 2, 5].map(|column| keycode(0b01, column, 2)),
+			[0xA6, 0xA7, 0xA5]
+		);
+	}
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::Ordering;
+
 use embassy_rp::{
 	gpio::{Input, Level, Output, Pull},
 	peripherals::{
@@ -5,16 +7,21 @@ use embassy_rp::{
 	},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
+
+use crate::usb;
 
 pub const KEY_BOUNCE_THRESHOLD: u8 = 20;
+const SCAN_INTERVAL_US: u64 = 100;
+const SUSPENDED_SCAN_INTERVAL_US: u64 = 1000;
 
 pub static EVENTS: Channel<CriticalSectionRawMutex, Event, 64> = Channel::new();
 
 #[derive(Clone)]
 pub enum Event {
-	Down(u8, u8),
-	Up(u8, u8),
+	/// A key was debounced as pressed at the given time.
+	Down(u8, u8, Instant),
+	Up(u8, u8, Instant),
 }
 
 pub struct KeyprobeConfig {
@@ -82,10 +89,14 @@ pub async fn keyprobe_task(keyboard_config: KeyprobeConfig) {
 
 				match (last_state, new_state) {
 					(1, 0) => {
-						EVENTS.send(Event::Up(x as u8, y as u8)).await;
+						EVENTS
+							.send(Event::Up(x as u8, y as u8, Instant::now()))
+							.await;
 					}
 					(l, n) if l == (KEY_BOUNCE_THRESHOLD - 1) && n == KEY_BOUNCE_THRESHOLD => {
-						EVENTS.send(Event::Down(x as u8, y as u8)).await;
+						EVENTS
+							.send(Event::Down(x as u8, y as u8, Instant::now()))
+							.await;
 					}
 					_ => {}
 				}
@@ -94,6 +105,10 @@ pub async fn keyprobe_task(keyboard_config: KeyprobeConfig) {
 			output.set_low();
 		}
 
-		Timer::after_micros(100).await;
+		if usb::SUSPENDED.load(Ordering::Relaxed) {
+			Timer::after_micros(SUSPENDED_SCAN_INTERVAL_US).await;
+		} else {
+			Timer::after_micros(SCAN_INTERVAL_US).await;
+		}
 	}
 }
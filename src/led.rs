@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use embassy_futures::select::{Either, select};
 use embassy_rp::{
 	gpio::{Level, Output},
@@ -6,8 +8,18 @@ use embassy_rp::{
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::Timer;
 
+use crate::usb;
+
 pub static LED_STATE: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
 
+/// When set, the LED double-blinks instead of staying off.
+static WARNING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_warning(warning: bool) {
+	WARNING.store(warning, Ordering::Relaxed);
+	LED_STATE.signal(LedState::Off);
+}
+
 #[derive(Clone, Copy, Default)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -36,7 +48,13 @@ pub async fn led_task(config: LedConfig) {
 			}
 			LedState::Off => {
 				led_pin.set_low();
-				LED_STATE.wait().await
+				if WARNING.load(Ordering::Relaxed) && !usb::SUSPENDED.load(Ordering::Relaxed) {
+					let Either::Second(r) =
+						select(led_blink_warning(&mut led_pin), LED_STATE.wait()).await;
+					r
+				} else {
+					LED_STATE.wait().await
+				}
 			}
 			LedState::BlinkSlow => {
 				let Either::Second(r) =
@@ -69,3 +87,15 @@ async fn led_blink_slow(led_pin: &mut Output<'_>) -> ! {
 		Timer::after_millis(500).await;
 	}
 }
+
+async fn led_blink_warning(led_pin: &mut Output<'_>) -> ! {
+	loop {
+		for _ in 0..2 {
+			led_pin.set_high();
+			Timer::after_millis(80).await;
+			led_pin.set_low();
+			Timer::after_millis(120).await;
+		}
+		Timer::after_millis(1000).await;
+	}
+}
@@ -1,17 +1,34 @@
 // Based entirely on the stars example from:
 // https://people.ece.cornell.edu/land/courses/ece4760/labs/s2021/stars/stars.html
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use embassy_rp::{
 	clocks::RoscRng,
 	i2c::{self, Async, I2c},
 	peripherals::{I2C1, PIN_2, PIN_3},
 };
-use embassy_time::Timer;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::{
+	mono_font::{MonoTextStyle, ascii::FONT_5X8},
+	pixelcolor::BinaryColor,
+	prelude::*,
+	primitives::{Line, PrimitiveStyle, Rectangle},
+	text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 
-use crate::frames;
+use crate::{
+	animation::{self, Player, Trigger},
+	framebuffer::{self, Framebuffer},
+	frames,
+	settings::{self, DisplaySettings},
+	state::{self, SharedState},
+	uart, usb,
+};
 
-const SZ: usize = 128 * 32 / 8;
+const SZ: usize = framebuffer::SIZE;
 const OLED_ADDR: u16 = 0x3C;
 const FREQUENCY: u32 = 200_000;
 const STAR_TOTAL: usize = 30;
@@ -21,6 +38,19 @@ const MAX_LIFETIME: u16 = 1000;
 const LONG_LIVED_CHANCE: u16 = 10;
 const LONG_LIVED_MULTIPLIER: u16 = 10;
 const MAX_SPAWN_COUNT: usize = STAR_TOTAL * 4;
+const SUSPEND_POLL_MS: u64 = 100;
+const DIM_CONTRAST: u8 = 0x08;
+/// How long after the last keypress or encoder turn, on either half, the
+/// display dims. `Duration::MAX` keeps it bright.
+pub const DIM_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long after the last keypress or encoder turn, on either half, the
+/// display turns off. `Duration::MAX` keeps it on.
+pub const OFF_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often the picture moves by a pixel, so that static content
+/// (the status screen, the idle animation) doesn't burn in.
+const SHIFT_INTERVAL: Duration = Duration::from_secs(60);
+/// The offsets the picture cycles through, right and down.
+static SHIFT_OFFSETS: [(u32, usize); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
 
 static DEATH_FADEOUT_LUT: &[u8] = &[0b1101_1111, 0b1110_1001, 0b1001_1000, 0b1100_0001];
 const DEATH_TIME: u16 = (DEATH_FADEOUT_LUT.len() * 8) as u16;
@@ -29,9 +59,51 @@ static mut BUFFERS: [[u8; SZ]; 2] = [[0; SZ]; 2];
 
 static mut SPAWN_COUNT: usize = 0;
 
+/// Raised on every keypress and encoder turn, on either half.
+static ACTIVITY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the split link counters are shown instead of the starfield.
+/// Toggled on the master, which mirrors it to the other half in
+/// `SharedState`.
+static DEBUG_SCREEN: AtomicBool = AtomicBool::new(false);
+
+/// 3x5 glyphs, one bit per pixel, left to right and top to bottom.
+#[rustfmt::skip]
+static DIGITS: [u16; 10] = [
+	0b111_101_101_101_111, 0b010_110_010_010_111, 0b111_001_111_100_111,
+	0b111_001_111_001_111, 0b101_101_111_001_001, 0b111_100_111_001_111,
+	0b111_100_111_101_111, 0b111_001_010_010_010, 0b111_101_111_101_111,
+	0b111_101_111_001_111,
+];
+
+/// Labels for the rows of the debug screen: baud (in kbit/s), frames sent
+/// and received, CRC errors, resyncs, retransmissions (X) and drops.
+#[rustfmt::skip]
+static STAT_LABELS: [u16; 7] = [
+	0b110_101_110_101_110, 0b111_010_010_010_010, 0b110_101_110_101_101,
+	0b111_100_100_100_111, 0b101_101_010_010_010, 0b101_101_010_101_101,
+	0b110_101_101_101_110,
+];
+
+/// Status screen labels for Ctrl, Shift, Alt and GUI, either side.
+static MODIFIER_LABELS: [&str; 4] = ["C", "S", "A", "G"];
+
 #[expect(non_camel_case_types)]
 type fx16 = ::fixed::FixedI32<::fixed::types::extra::U16>;
 
+pub fn toggle_debug_screen() {
+	DEBUG_SCREEN.store(!DEBUG_SCREEN.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+pub fn debug_screen() -> bool {
+	DEBUG_SCREEN.load(Ordering::Relaxed)
+}
+
+/// Restarts the idle timer, waking the display if it was off.
+pub fn wake() {
+	ACTIVITY.signal(());
+}
+
 pub fn spawn_star() {
 	unsafe {
 		SPAWN_COUNT += 1;
@@ -80,13 +152,24 @@ pub async fn oled_task(config: OledConfig) -> ! {
 		i2c_config,
 	);
 
-	init(&mut i2c).await;
+	let mut display = settings::display();
+
+	init(&mut i2c, &display).await;
 	clear(&mut i2c).await;
 
 	let mut buffer_idx = 0;
 	let mut rng = SmallRng::from_rng(RoscRng).unwrap();
 
 	let mut frame_counter: usize = 0;
+	let mut status = SharedState {
+		display,
+		..Default::default()
+	};
+	let mut player = Player::new(Instant::now());
+	let mut last_activity = Instant::now();
+	let mut dimmed = false;
+
+	animation::trigger(Trigger::Boot);
 
 	let mut xpos_avg: fx16;
 	let mut ypos_avg: fx16;
@@ -98,6 +181,33 @@ pub async fn oled_task(config: OledConfig) -> ! {
 
 	#[expect(static_mut_refs)]
 	loop {
+		if usb::SUSPENDED.load(Ordering::Relaxed) {
+			set_display_on(&mut i2c, false).await;
+			while usb::SUSPENDED.load(Ordering::Relaxed) {
+				Timer::after_millis(SUSPEND_POLL_MS).await;
+			}
+			set_display_on(&mut i2c, true).await;
+		}
+
+		if ACTIVITY.try_take().is_some() {
+			last_activity = Instant::now();
+		}
+
+		let idle = Instant::now() - last_activity;
+
+		if idle >= OFF_TIMEOUT {
+			set_display_on(&mut i2c, false).await;
+			ACTIVITY.wait().await;
+			last_activity = Instant::now();
+
+			dimmed = false;
+			set_contrast(&mut i2c, contrast(&display, dimmed)).await;
+			set_display_on(&mut i2c, true).await;
+		} else if (idle >= DIM_TIMEOUT) != dimmed {
+			dimmed = !dimmed;
+			set_contrast(&mut i2c, contrast(&display, dimmed)).await;
+		}
+
 		unsafe {
 			SPAWN_COUNT = SPAWN_COUNT.min(MAX_SPAWN_COUNT);
 		}
@@ -107,7 +217,8 @@ pub async fn oled_task(config: OledConfig) -> ! {
 		let buffer = unsafe { &mut BUFFERS[buffer_idx] };
 		buffer_idx = 1 - buffer_idx;
 
-		buffer.fill(0);
+		let mut fb = Framebuffer::new(buffer);
+		fb.clear(BinaryColor::Off).ok();
 
 		let protected_range_squared = PROTECTED_RANGE * PROTECTED_RANGE;
 		let visual_range_squared = VISUAL_RANGE * VISUAL_RANGE;
@@ -277,14 +388,7 @@ pub async fn oled_task(config: OledConfig) -> ! {
 					let x = x.max(0).min(31);
 					let y = y.max(0).min(127);
 
-					let idx = y as usize * 32 + x as usize;
-					let byte = idx / 8;
-					let bit = idx % 8;
-					let mask = 1_u8 << bit;
-
-					buffer.get_mut(byte).map(|b| {
-						*b |= mask;
-					});
+					fb.set_pixel(Point::new(x, y), BinaryColor::On);
 				}
 			}
 
@@ -303,13 +407,173 @@ pub async fn oled_task(config: OledConfig) -> ! {
 			}
 		}
 
-		send_buffer(&mut i2c, buffer).await;
+		if let Some(shared) = state::STATE.try_take() {
+			if shared.layers != status.layers {
+				animation::trigger(Trigger::LayerChange);
+			}
+			if shared.display != display {
+				display = shared.display;
+				// Keeps the other half's settings, mirrored from the master.
+				settings::set_display(display);
+				apply_display(&mut i2c, &display, dimmed).await;
+			}
+			status = shared;
+		}
+
+		// Underneath the animations, so that they play on the master too.
+		if state::MASTER.load(Ordering::Relaxed) && !status.debug_screen {
+			fb.clear(BinaryColor::Off).ok();
+			draw_status(&mut fb, &status);
+		}
+
+		for sprite in player.update(Instant::now()) {
+			apply_mask(fb.buffer(), sprite.frame, sprite.x, sprite.y);
+		}
+		if let Some(clip) = player.clip() {
+			clip.draw(fb.buffer());
+		}
+
+		if status.debug_screen {
+			fb.clear(BinaryColor::Off).ok();
+			draw_link_stats(&mut fb);
+		}
+
+		let shift = Instant::now().as_ticks() / SHIFT_INTERVAL.as_ticks();
+		let (dx, dy) = SHIFT_OFFSETS[shift as usize % SHIFT_OFFSETS.len()];
+		shift_buffer(fb.buffer(), dx, dy);
+
+		// Blink a border around the screen if the halves run different firmware.
+		if uart::FIRMWARE_MISMATCH.load(Ordering::Relaxed) && (frame_counter / 60) % 2 == 0 {
+			draw_border(&mut fb);
+		}
+
+		send_buffer(&mut i2c, fb.buffer()).await;
 
 		Timer::after_millis(1000 / 120).await;
 	}
 }
 
-fn apply_mask(buffer: &mut [u8; 128 * 32 / 8], frame: &frames::Frame, pos_x: usize, pos_y: usize) {
+/// Moves the picture `dx` pixels right and `dy` down, dropping whatever
+/// falls off the edge.
+fn shift_buffer(buffer: &mut [u8; SZ], dx: u32, dy: usize) {
+	const ROW: usize = framebuffer::WIDTH / 8;
+
+	buffer.copy_within(..SZ - dy * ROW, dy * ROW);
+	buffer[..dy * ROW].fill(0);
+
+	// Leftmost pixel in the least significant bit.
+	for row in buffer.chunks_exact_mut(ROW) {
+		let pixels = u32::from_le_bytes([row[0], row[1], row[2], row[3]]) << dx;
+		row.copy_from_slice(&pixels.to_le_bytes());
+	}
+}
+
+fn draw_border(fb: &mut Framebuffer) {
+	fb.bounding_box()
+		.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+		.draw(fb)
+		.ok();
+}
+
+/// The master's screen: layer, held modifiers, lock LEDs and USB state,
+/// top to bottom. Ends above the sigils and the body (see `animation`),
+/// which play over it.
+fn draw_status(fb: &mut Framebuffer, status: &SharedState) {
+	draw_label(fb, crate::layer_name(status.layers), 16, 1, false);
+	draw_separator(fb, 11);
+
+	// Left and right modifiers share a cell.
+	let modifiers = status.modifiers | status.modifiers >> 4;
+	for (i, label) in MODIFIER_LABELS.iter().enumerate() {
+		let x = 2 + (i as i32 % 2) * 15;
+		let y = 13 + (i as i32 / 2) * 12;
+		draw_label(fb, label, x + 6, y + 2, modifiers & (1 << i) != 0);
+	}
+	draw_separator(fb, 38);
+
+	draw_label(fb, "CAPS", 16, 41, status.host_leds & 0b10 != 0);
+	draw_label(fb, "NUM", 16, 52, status.host_leds & 0b01 != 0);
+	draw_separator(fb, 63);
+
+	let usb_state = if status.suspended {
+		"SLP"
+	} else if usb::is_configured() {
+		"ON"
+	} else {
+		"--"
+	};
+	draw_label(fb, "USB", 16, 65, false);
+	draw_label(fb, usb_state, 16, 75, false);
+}
+
+/// Draws `text` centered on `x`, or inverted on a filled box when `lit`.
+fn draw_label(fb: &mut Framebuffer, text: &str, x: i32, y: i32, lit: bool) {
+	let style = TextStyleBuilder::new()
+		.alignment(Alignment::Center)
+		.baseline(Baseline::Top)
+		.build();
+	let color = if lit {
+		BinaryColor::Off
+	} else {
+		BinaryColor::On
+	};
+	let text = Text::with_text_style(
+		text,
+		Point::new(x, y),
+		MonoTextStyle::new(&FONT_5X8, color),
+		style,
+	);
+
+	if lit {
+		text.bounding_box()
+			.offset(1)
+			.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+			.draw(fb)
+			.ok();
+	}
+
+	text.draw(fb).ok();
+}
+
+fn draw_separator(fb: &mut Framebuffer, y: i32) {
+	Line::new(Point::new(2, y), Point::new(29, y))
+		.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+		.draw(fb)
+		.ok();
+}
+
+fn draw_link_stats(fb: &mut Framebuffer) {
+	let stats = uart::LINK.stats.snapshot();
+	let values = [uart::LINK.baud() / 1000].into_iter().chain(stats);
+
+	for (row, (label, value)) in STAT_LABELS.iter().zip(values).enumerate() {
+		let y = 2 + row as i32 * 8;
+		draw_glyph(fb, *label, 0, y);
+
+		// Right-aligned, keeping the last six digits.
+		let mut value = value % 1_000_000;
+		for column in (2..8).rev() {
+			draw_glyph(fb, DIGITS[(value % 10) as usize], column * 4, y);
+			value /= 10;
+			if value == 0 {
+				break;
+			}
+		}
+	}
+}
+
+fn draw_glyph(fb: &mut Framebuffer, glyph: u16, x: i32, y: i32) {
+	let glyph_box = Rectangle::new(Point::new(x, y), Size::new(3, 5));
+	let pixels = glyph_box
+		.points()
+		.enumerate()
+		.filter(|&(bit, _)| glyph & (1 << (14 - bit)) != 0)
+		.map(|(_, point)| Pixel(point, BinaryColor::On));
+
+	fb.draw_iter(pixels).ok();
+}
+
+fn apply_mask(buffer: &mut [u8; SZ], frame: &frames::Frame, pos_x: usize, pos_y: usize) {
 	let ox = pos_x.min(32 - frame.width) / 8;
 	let oy = pos_y.min(128 - frame.height);
 	let w = (frame.width + 7) / 8;
@@ -349,7 +613,40 @@ async fn send_buffer(i2c: &mut I2c<'_, I2C1, Async>, oled_buffer: &[u8]) {
 	.ok();
 }
 
-async fn init(i2c: &mut I2c<'_, I2C1, Async>) -> bool {
+fn contrast(display: &DisplaySettings, dimmed: bool) -> u8 {
+	if dimmed {
+		DIM_CONTRAST.min(display.contrast)
+	} else {
+		display.contrast
+	}
+}
+
+async fn apply_display(i2c: &mut I2c<'_, I2C1, Async>, display: &DisplaySettings, dimmed: bool) {
+	let (segment_remap, com_scan) = if display.rotated {
+		(0xA1, 0xC0)
+	} else {
+		(0xA0, 0xC8)
+	};
+	let inverse = if display.inverted { 0xA7 } else { 0xA6 };
+
+	for cmd in [segment_remap, com_scan, inverse] {
+		i2c.write_async(OLED_ADDR, [0x00, cmd]).await.ok();
+	}
+	set_contrast(i2c, contrast(display, dimmed)).await;
+}
+
+async fn set_contrast(i2c: &mut I2c<'_, I2C1, Async>, contrast: u8) {
+	i2c.write_async(OLED_ADDR, [0x00, 0x81, contrast])
+		.await
+		.ok();
+}
+
+async fn set_display_on(i2c: &mut I2c<'_, I2C1, Async>, on: bool) {
+	let cmd = if on { 0xAF } else { 0xAE };
+	i2c.write_async(OLED_ADDR, [0x00, cmd]).await.ok();
+}
+
+async fn init(i2c: &mut I2c<'_, I2C1, Async>, display: &DisplaySettings) -> bool {
 	macro_rules! write_cmd {
 		($($data:expr),*) => {
 			i2c.write_async(OLED_ADDR, [0x00, $($data),*]).await.map_err(|_| ())?;
@@ -361,12 +658,12 @@ async fn init(i2c: &mut I2c<'_, I2C1, Async>) -> bool {
 		write_cmd!(0xA8, 0x1F); // set MUX Ratio
 		write_cmd!(0xD3, 0x00); // set display offset
 		write_cmd!(0x40 | 0x0); // memory Start
-		write_cmd!(0xA0); // normal x
-		write_cmd!(0xC8); // COM output mode
+		write_cmd!(if display.rotated { 0xA1 } else { 0xA0 }); // A0 for normal x, A1 for mirrored
+		write_cmd!(if display.rotated { 0xC0 } else { 0xC8 }); // COM output mode
 		write_cmd!(0xDA, 0x02); // COM pin hardware configuration
-		write_cmd!(0x81, 0x7F); // contrast max
+		write_cmd!(0x81, display.contrast); // contrast
 		write_cmd!(0xA4); // A5 for on, A4 for use RAM
-		write_cmd!(0xA6); // A6 for Normal/A7 for inverse
+		write_cmd!(if display.inverted { 0xA7 } else { 0xA6 }); // A6 for Normal/A7 for inverse
 		write_cmd!(0xD5, 0x01); // set oscolation frequency
 		write_cmd!(0x8D, 0x14); // set charge pump
 		write_cmd!(0xAF); // turn on screen
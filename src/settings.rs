@@ -0,0 +1,182 @@
+//! Display settings, kept in the last sector of flash.
+//!
+//! The master applies actions to its settings and mirrors them to the
+//! other half in `SharedState`; each half saves what it's showing, so
+//! both come back the same after a reboot.
+
+use core::cell::Cell;
+#[cfg(target_os = "none")]
+use core::cell::RefCell;
+
+#[cfg(target_os = "none")]
+use embassy_rp::{
+	flash::{Blocking, ERASE_SIZE, Flash},
+	peripherals::FLASH,
+};
+#[cfg(target_os = "none")]
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::{
+	blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+	signal::Signal,
+};
+#[cfg(target_os = "none")]
+use embassy_time::{Duration, with_timeout};
+#[cfg(target_os = "none")]
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::state;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The flash, shared between the tasks that write to it (all of which
+/// run on the same executor).
+#[cfg(target_os = "none")]
+pub type SharedFlash = Mutex<NoopRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+/// Kept clear of the firmware in `memory.x` and `memory-boot.x`.
+#[cfg(target_os = "none")]
+const SETTINGS_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+#[cfg(target_os = "none")]
+const MAGIC: u8 = 0x5E;
+
+const CONTRAST_STEP: u8 = 0x10;
+/// Turned all the way down, the display should still be visible.
+const MIN_CONTRAST: u8 = 0x01;
+
+/// How long the settings have to stay put before they're saved, so that
+/// turning the encoder through the contrast range is a single write.
+#[cfg(target_os = "none")]
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DisplaySettings {
+	pub contrast: u8,
+	pub inverted: bool,
+	/// Turned 180 degrees.
+	pub rotated:  bool,
+}
+
+const DEFAULT: DisplaySettings = DisplaySettings {
+	contrast: 0x7F,
+	inverted: false,
+	rotated:  false,
+};
+
+impl Default for DisplaySettings {
+	fn default() -> Self {
+		DEFAULT
+	}
+}
+
+impl DisplaySettings {
+	pub fn flags(&self) -> u8 {
+		self.inverted as u8 | (self.rotated as u8) << 1
+	}
+
+	pub fn from_flags(contrast: u8, flags: u8) -> Self {
+		Self {
+			contrast,
+			inverted: flags & 0b01 != 0,
+			rotated: flags & 0b10 != 0,
+		}
+	}
+}
+
+static DISPLAY: Mutex<CriticalSectionRawMutex, Cell<DisplaySettings>> =
+	Mutex::new(Cell::new(DEFAULT));
+
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn display() -> DisplaySettings {
+	DISPLAY.lock(|display| display.get())
+}
+
+/// Changes the settings, saving them (eventually) and letting the master
+/// publish them.
+pub fn set_display(settings: DisplaySettings) {
+	if display() != settings {
+		DISPLAY.lock(|display| display.set(settings));
+		CHANGED.signal(());
+		state::DIRTY.signal(());
+	}
+}
+
+pub fn step_contrast(up: bool) {
+	let mut settings = display();
+	settings.contrast = if up {
+		settings.contrast.saturating_add(CONTRAST_STEP)
+	} else {
+		settings
+			.contrast
+			.saturating_sub(CONTRAST_STEP)
+			.max(MIN_CONTRAST)
+	};
+	set_display(settings);
+}
+
+pub fn toggle_inverted() {
+	let mut settings = display();
+	settings.inverted = !settings.inverted;
+	set_display(settings);
+}
+
+pub fn toggle_rotated() {
+	let mut settings = display();
+	settings.rotated = !settings.rotated;
+	set_display(settings);
+}
+
+/// Reads the saved settings, if there are any. Must run before anything
+/// reads `display()`.
+#[cfg(target_os = "none")]
+pub fn load(flash: &SharedFlash) {
+	let mut record = [0; 4];
+	let read = flash.lock(|flash| flash.borrow_mut().read(SETTINGS_OFFSET, &mut record));
+
+	if let (Ok(()), [MAGIC, contrast, flags, check]) = (read, record) {
+		if check == MAGIC ^ contrast ^ flags {
+			let settings = DisplaySettings::from_flags(contrast, flags);
+			DISPLAY.lock(|display| display.set(settings));
+		}
+	}
+}
+
+#[cfg(target_os = "none")]
+fn save(flash: &SharedFlash, settings: DisplaySettings) {
+	let contrast = settings.contrast;
+	let flags = settings.flags();
+	let record = [MAGIC, contrast, flags, MAGIC ^ contrast ^ flags];
+
+	flash.lock(|flash| {
+		let mut flash = flash.borrow_mut();
+
+		// Spares the sector an erase when it already holds these, e.g. when
+		// the master mirrors back what this half loaded.
+		let mut stored = [0; 4];
+		if flash.read(SETTINGS_OFFSET, &mut stored).is_ok() && stored == record {
+			return;
+		}
+
+		flash
+			.erase(SETTINGS_OFFSET, SETTINGS_OFFSET + ERASE_SIZE as u32)
+			.ok();
+		flash.write(SETTINGS_OFFSET, &record).ok();
+	});
+}
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task]
+pub async fn settings_task(flash: &'static SharedFlash) -> ! {
+	let mut saved = display();
+
+	loop {
+		CHANGED.wait().await;
+		while with_timeout(SAVE_DELAY, CHANGED.wait()).await.is_ok() {}
+
+		let settings = display();
+		if settings != saved {
+			save(flash, settings);
+			saved = settings;
+		}
+	}
+}
@@ -0,0 +1,29 @@
+use core::sync::atomic::AtomicBool;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+use crate::settings::DisplaySettings;
+
+/// The latest keyboard state, as published by the master half (and
+/// mirrored to the other half over the split link).
+pub static STATE: Signal<CriticalSectionRawMutex, SharedState> = Signal::new();
+
+/// Whether this half is the master, i.e. the one turning key events
+/// into reports for its USB host.
+pub static MASTER: AtomicBool = AtomicBool::new(false);
+
+/// Raised when something outside of `run_alchemist` changed state
+/// that should be published (e.g. the host toggling Caps Lock).
+pub static DIRTY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct SharedState {
+	pub layers:       u8,
+	pub modifiers:    u8,
+	/// The host's keyboard LEDs (bit 0 is Num Lock, bit 1 Caps Lock, ...).
+	pub host_leds:    u8,
+	pub suspended:    bool,
+	pub display:      DisplaySettings,
+	/// Whether both halves show the split link counters.
+	pub debug_screen: bool,
+}
@@ -0,0 +1,210 @@
+//! The byte transports the split link can run over.
+
+#[cfg(target_os = "none")]
+use embassy_futures::select::{Either, select};
+#[cfg(target_os = "none")]
+use embassy_rp::{
+	Peripheral,
+	clocks::clk_sys_freq,
+	gpio::Level,
+	peripherals::PIO0,
+	pio::{Common, Config, Direction, FifoJoin, PioPin, ShiftConfig, ShiftDirection, StateMachine},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+#[cfg(target_os = "none")]
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex, signal::Signal};
+#[cfg(target_os = "none")]
+use fixed::{
+	FixedU32,
+	traits::ToFixed,
+	types::{U56F8, extra::U8},
+};
+
+/// Carries encoded frames between the two halves.
+///
+/// The link reads and writes concurrently, so both take `&self`.
+#[allow(async_fn_in_trait)]
+pub trait SplitTransport {
+	/// Sends a whole frame to the other half.
+	async fn send(&self, frame: &[u8]);
+	/// Waits for the next byte from the other half. Dropping the future
+	/// must not lose a byte that hasn't been returned.
+	async fn receive(&self) -> u8;
+	/// Changes the bit rate on this end. Transports without one ignore it.
+	fn set_baud(&self, _baud: u32) {}
+}
+
+/// Runs the link over a pair of PIO state machines, one sending and one
+/// receiving 8n1 UART.
+///
+/// The state machines are kept (rather than wrapped in `PioUartTx` and
+/// `PioUartRx`) so that the rate can be changed through them.
+#[cfg(target_os = "none")]
+pub struct PioTransport<'d, const TX: usize, const RX: usize> {
+	tx:         Mutex<NoopRawMutex, StateMachine<'d, PIO0, TX>>,
+	rx:         Mutex<NoopRawMutex, StateMachine<'d, PIO0, RX>>,
+	/// Clock dividers for a new rate, applied by the next `send()` and by
+	/// `receive()` as soon as it sees them.
+	tx_divider: Signal<NoopRawMutex, FixedU32<U8>>,
+	rx_divider: Signal<NoopRawMutex, FixedU32<U8>>,
+}
+
+#[cfg(target_os = "none")]
+impl<'d, const TX: usize, const RX: usize> PioTransport<'d, TX, RX> {
+	pub fn new(
+		common: &mut Common<'d, PIO0>,
+		mut sm_tx: StateMachine<'d, PIO0, TX>,
+		mut sm_rx: StateMachine<'d, PIO0, RX>,
+		tx_pin: impl Peripheral<P = impl PioPin + 'd> + 'd,
+		rx_pin: impl Peripheral<P = impl PioPin + 'd> + 'd,
+		baud: u32,
+	) -> Self {
+		// The same programs as `PioUartTx` and `PioUartRx`.
+		let tx_program = pio_proc::pio_asm!(
+			r#"
+				.side_set 1 opt
+				pull            side 1 [7]
+				set x, 7        side 0 [7]
+			bitloop:
+				out pins, 1
+				jmp x-- bitloop        [6]
+			"#
+		);
+
+		let rx_program = pio_proc::pio_asm!(
+			r#"
+			start:
+				wait 0 pin 0
+				set x, 7        [10]
+			bitloop:
+				in pins, 1
+				jmp x-- bitloop [6]
+				jmp pin good_stop
+				wait 1 pin 0
+				jmp start
+			good_stop:
+				push
+			"#
+		);
+
+		let tx_pin = common.make_pio_pin(tx_pin);
+		sm_tx.set_pins(Level::High, &[&tx_pin]);
+		sm_tx.set_pin_dirs(Direction::Out, &[&tx_pin]);
+
+		let mut cfg = Config::default();
+		cfg.use_program(&common.load_program(&tx_program.program), &[&tx_pin]);
+		cfg.set_out_pins(&[&tx_pin]);
+		cfg.shift_out = ShiftConfig {
+			auto_fill: false,
+			threshold: 32,
+			direction: ShiftDirection::Right,
+		};
+		cfg.fifo_join = FifoJoin::TxOnly;
+		cfg.clock_divider = divider(baud);
+		sm_tx.set_config(&cfg);
+		sm_tx.set_enable(true);
+
+		let rx_pin = common.make_pio_pin(rx_pin);
+		sm_rx.set_pin_dirs(Direction::In, &[&rx_pin]);
+
+		let mut cfg = Config::default();
+		cfg.use_program(&common.load_program(&rx_program.program), &[]);
+		cfg.set_in_pins(&[&rx_pin]);
+		cfg.set_jmp_pin(&rx_pin);
+		cfg.shift_in = ShiftConfig {
+			auto_fill: false,
+			threshold: 32,
+			direction: ShiftDirection::Right,
+		};
+		cfg.fifo_join = FifoJoin::RxOnly;
+		cfg.clock_divider = divider(baud);
+		sm_rx.set_config(&cfg);
+		sm_rx.set_enable(true);
+
+		Self {
+			tx:         Mutex::new(sm_tx),
+			rx:         Mutex::new(sm_rx),
+			tx_divider: Signal::new(),
+			rx_divider: Signal::new(),
+		}
+	}
+}
+
+/// Eight PIO cycles per bit.
+#[cfg(target_os = "none")]
+fn divider(baud: u32) -> FixedU32<U8> {
+	(U56F8::from_num(clk_sys_freq()) / U56F8::from_num(8 * baud)).to_fixed()
+}
+
+#[cfg(target_os = "none")]
+impl<const TX: usize, const RX: usize> SplitTransport for PioTransport<'_, TX, RX> {
+	async fn send(&self, frame: &[u8]) {
+		let mut sm = self.tx.lock().await;
+
+		if let Some(divider) = self.tx_divider.try_take() {
+			sm.set_clock_divider(divider);
+			sm.clkdiv_restart();
+		}
+
+		for &byte in frame {
+			sm.tx().wait_push(byte as u32).await;
+		}
+	}
+
+	async fn receive(&self) -> u8 {
+		let mut sm = self.rx.lock().await;
+
+		loop {
+			// Pulling the byte out of the FIFO is the last step, so dropping
+			// this doesn't lose one.
+			match select(sm.rx().wait_pull(), self.rx_divider.wait()).await {
+				Either::First(word) => return (word >> 24) as u8,
+				Either::Second(divider) => {
+					sm.set_clock_divider(divider);
+					sm.clkdiv_restart();
+				}
+			}
+		}
+	}
+
+	fn set_baud(&self, baud: u32) {
+		self.tx_divider.signal(divider(baud));
+		self.rx_divider.signal(divider(baud));
+	}
+}
+
+/// One end of an in-memory link, so that both halves can run in a single
+/// process without any hardware.
+pub struct ChannelTransport<'a, const N: usize> {
+	tx: &'a Pipe<CriticalSectionRawMutex, N>,
+	rx: &'a Pipe<CriticalSectionRawMutex, N>,
+}
+
+impl<'a, const N: usize> ChannelTransport<'a, N> {
+	pub const fn new(
+		tx: &'a Pipe<CriticalSectionRawMutex, N>,
+		rx: &'a Pipe<CriticalSectionRawMutex, N>,
+	) -> Self {
+		Self { tx, rx }
+	}
+
+	/// Connects two ends through a pipe in each direction.
+	pub const fn pair(
+		a: &'a Pipe<CriticalSectionRawMutex, N>,
+		b: &'a Pipe<CriticalSectionRawMutex, N>,
+	) -> (Self, Self) {
+		(Self::new(a, b), Self::new(b, a))
+	}
+}
+
+impl<const N: usize> SplitTransport for ChannelTransport<'_, N> {
+	async fn send(&self, frame: &[u8]) {
+		self.tx.write_all(frame).await;
+	}
+
+	async fn receive(&self) -> u8 {
+		let mut byte = [0; 1];
+		self.rx.read(&mut byte).await;
+		byte[0]
+	}
+}
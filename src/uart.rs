@@ -1,65 +1,639 @@
-use embassy_futures::join::join;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_futures::{
+	join::join,
+	select::{Either4, select4},
+};
+#[cfg(target_os = "none")]
 use embassy_rp::{
 	peripherals::{PIN_1, PIN_4, PIO0},
 	pio,
-	pio_programs::uart::{PioUartRx, PioUartRxProgram, PioUartTx, PioUartTxProgram},
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embedded_io_async::{Read, Write};
+use embassy_sync::{
+	blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_deadline};
+use heapless::Vec;
+use portable_atomic::AtomicU32;
+
+#[cfg(all(target_os = "none", not(feature = "half-duplex")))]
+use crate::transport::PioTransport;
+use crate::{
+	BoardSide,
+	framing::{DecodeError, Decoder, MAX_FRAME_SIZE, MAX_PAYLOAD, encode_frame},
+	settings::DisplaySettings,
+	state::SharedState,
+	transport::SplitTransport,
+};
+#[cfg(all(target_os = "none", feature = "half-duplex"))]
+use crate::{half_duplex, transport::ChannelTransport};
+
+/// The firmware's end of the split link, run by `uart_task`.
+pub static LINK: Link = Link::new();
+
+/// Whether the other half announced a different protocol version or build.
+pub static FIRMWARE_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// Bumped whenever the meaning of any packet changes.
+pub const PROTOCOL_VERSION: u8 = 5;
+
+include!(concat!(env!("OUT_DIR"), "/build_hash.rs"));
+
+/// The rates the link may run at, slowest first. It always comes up at the
+/// first one, and the left half then negotiates upwards.
+#[cfg(not(feature = "half-duplex"))]
+const RATES: [u32; 5] = [115_200, 230_400, 460_800, 921_600, 1_843_200];
+#[cfg(feature = "half-duplex")]
+const RATES: [u32; 1] = [115_200];
+
+/// The most image bytes a `Packet::UpdateChunk` carries.
+pub const UPDATE_CHUNK_SIZE: usize = 56;
+
+/// The bits of a `Packet::Matrix` that correspond to keys.
+pub const MATRIX_MASK: u32 = (1 << 30) - 1;
+
+/// Set in the type byte of a data frame that is being sent again.
+const RETRANSMIT: u8 = 0x80;
+/// Set in the type byte of a data frame when every packet sent before it
+/// was either acknowledged or given up on.
+const OLDEST: u8 = 0x40;
+const KIND_ACK: u8 = 0x70;
+const KIND_NAK: u8 = 0x71;
+const KIND_HEARTBEAT: u8 = 0x72;
+const KIND_TURN: u8 = 0x73;
+const KIND_RATE: u8 = 0x74;
+const KIND_PROBE: u8 = 0x75;
+const KIND_PING: u8 = 0x76;
+const KIND_PONG: u8 = 0x77;
+
+/// How long to wait for an acknowledgement before retransmitting.
+#[cfg(not(feature = "half-duplex"))]
+const ACK_TIMEOUT: Duration = Duration::from_millis(10);
+/// Long enough for the turn to go around on the single wire.
+#[cfg(feature = "half-duplex")]
+const ACK_TIMEOUT: Duration = Duration::from_millis(40);
+/// How long the line must be quiet before a partial frame is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(2);
+/// How long the writer may stay quiet before it sends a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long without any frame from the other half before the link is
+/// considered down.
+const LINK_TIMEOUT: Duration = Duration::from_millis(350);
+/// How many times a packet is retransmitted before it's dropped.
+const MAX_RETRIES: u8 = 5;
+/// How far ahead of the oldest unacknowledged packet the sender may get.
+const MAX_UNACKED: usize = 4;
+/// How long a missing packet holds up the ones after it. By then, the
+/// other half has given up on it.
+const MISSING_TIMEOUT: Duration =
+	Duration::from_ticks(ACK_TIMEOUT.as_ticks() * (MAX_RETRIES as u64 + 2));
+/// How long to wait for the bytes already handed to the transport to go
+/// out before changing rate.
+const SWITCH_GUARD: Duration = Duration::from_millis(2);
+/// How long the left half waits for each step of a rate negotiation.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(20);
+/// How long the right half keeps a new rate without hearing a probe or
+/// the final confirmation.
+const TRIAL_TIMEOUT: Duration = Duration::from_millis(50);
+/// How many full-size probes must make the round trip before a rate is
+/// accepted.
+const PROBE_COUNT: u8 = 16;
+/// How often the left half checks the error rate.
+const ERROR_WINDOW: Duration = Duration::from_millis(1000);
+/// The most CRC errors and retransmissions per `ERROR_WINDOW` before the
+/// link drops to a slower rate.
+const MAX_ERRORS: u32 = 4;
+/// How long before a rate that failed is tried again.
+const RATE_BACKOFF: Duration = Duration::from_secs(60);
+/// How often each half measures the offset between the two clocks.
+const PING_INTERVAL: Duration = Duration::from_millis(1000);
+/// How long the clock offset from the fastest round trip is trusted over
+/// those from slower ones.
+const CLOCK_SAMPLE_LIFETIME: Duration = Duration::from_secs(10);
+
+/// One end of the split link, shared between its reader and writer and
+/// the rest of the firmware.
+pub struct Link {
+	/// Packets and link changes from the other half.
+	pub incoming: Channel<CriticalSectionRawMutex, Event, 64>,
+	/// Packets for the other half.
+	pub outgoing: Channel<CriticalSectionRawMutex, Packet, 64>,
+	/// Firmware update packets from the other half, which go straight to
+	/// `update` so that writing flash doesn't hold up `incoming`.
+	pub updates:  Channel<CriticalSectionRawMutex, Packet, 16>,
+	pub stats:    LinkStats,
+	/// Acknowledgements the reader wants the writer to send.
+	replies:      Channel<CriticalSectionRawMutex, Reply, 8>,
+	/// Acknowledgements the reader received from the other half.
+	peer_replies: Channel<CriticalSectionRawMutex, Reply, 8>,
+	/// Whether frames from the other half arrived within the last
+	/// `LINK_TIMEOUT`.
+	up:           AtomicBool,
+	/// The index in `RATES` of the rate the link currently runs at.
+	rate:         AtomicU8,
+	/// Our clock minus the other half's, in wrapping microseconds.
+	clock_offset: AtomicU32,
+	/// Whether `clock_offset` was measured since the link last came up.
+	clock_synced: AtomicBool,
+	/// Whether only retransmissions and replies may be sent.
+	held:         AtomicBool,
+	released:     Signal<CriticalSectionRawMutex, ()>,
+}
+
+/// Counters for diagnosing a flaky link. They only ever go up, wrapping
+/// around eventually.
+pub struct LinkStats {
+	pub frames_sent:     AtomicU32,
+	pub frames_received: AtomicU32,
+	pub crc_errors:      AtomicU32,
+	/// Times the decoder threw bytes away to find the start of a frame.
+	pub resyncs:         AtomicU32,
+	pub retransmissions: AtomicU32,
+	/// Packets and replies that never went out, because a queue was full
+	/// or the other half never acknowledged them.
+	pub dropped:         AtomicU32,
+}
+
+impl LinkStats {
+	const fn new() -> Self {
+		Self {
+			frames_sent:     AtomicU32::new(0),
+			frames_received: AtomicU32::new(0),
+			crc_errors:      AtomicU32::new(0),
+			resyncs:         AtomicU32::new(0),
+			retransmissions: AtomicU32::new(0),
+			dropped:         AtomicU32::new(0),
+		}
+	}
+
+	/// All counters, in declaration order.
+	pub fn snapshot(&self) -> [u32; 6] {
+		[
+			&self.frames_sent,
+			&self.frames_received,
+			&self.crc_errors,
+			&self.resyncs,
+			&self.retransmissions,
+			&self.dropped,
+		]
+		.map(|counter| counter.load(Ordering::Relaxed))
+	}
+
+	fn errors(&self) -> u32 {
+		self.crc_errors
+			.load(Ordering::Relaxed)
+			.wrapping_add(self.retransmissions.load(Ordering::Relaxed))
+	}
+}
+
+fn count(counter: &AtomicU32) {
+	counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Default for Link {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Link {
+	pub const fn new() -> Self {
+		Self {
+			incoming:     Channel::new(),
+			outgoing:     Channel::new(),
+			updates:      Channel::new(),
+			stats:        LinkStats::new(),
+			replies:      Channel::new(),
+			peer_replies: Channel::new(),
+			up:           AtomicBool::new(false),
+			rate:         AtomicU8::new(0),
+			clock_offset: AtomicU32::new(0),
+			clock_synced: AtomicBool::new(false),
+			held:         AtomicBool::new(false),
+			released:     Signal::new(),
+		}
+	}
+
+	/// Queues a packet for the other half without waiting, counting it as
+	/// dropped if the queue is full.
+	pub fn try_send(&self, packet: Packet) {
+		if self.outgoing.try_send(packet).is_err() {
+			count(&self.stats.dropped);
+		}
+	}
 
-use crate::BoardSide;
+	/// Keeps the line quiet, sending nothing but what's still owed to the
+	/// other half (retransmissions and replies), until `release()`. For
+	/// while the other half can't listen, such as when it writes to flash.
+	pub fn hold(&self) {
+		self.held.store(true, Ordering::Relaxed);
+	}
 
-pub static INCOMING: Channel<CriticalSectionRawMutex, Packet, 64> = Channel::new();
-pub static OUTGOING: Channel<CriticalSectionRawMutex, Packet, 64> = Channel::new();
+	pub fn release(&self) {
+		self.held.store(false, Ordering::Relaxed);
+		self.released.signal(());
+	}
 
-pub const PACKET_SIZE: usize = 3;
-const BAUD: u32 = 115200;
+	fn is_held(&self) -> bool {
+		self.held.load(Ordering::Relaxed)
+	}
+
+	/// Whether frames from the other half arrived within the last
+	/// `LINK_TIMEOUT`.
+	pub fn is_up(&self) -> bool {
+		self.up.load(Ordering::Relaxed)
+	}
+
+	/// The rate the link currently runs at.
+	pub fn baud(&self) -> u32 {
+		RATES[self.rate.load(Ordering::Relaxed) as usize]
+	}
+
+	/// Converts a timestamp from the other half to our clock. Until the
+	/// offset between the clocks is known, or if the result would lie in
+	/// the future, it's taken to be now.
+	pub fn remote_instant(&self, stamp: u32) -> Instant {
+		let now = Instant::now();
+
+		if !self.clock_synced.load(Ordering::Relaxed) {
+			return now;
+		}
+
+		let local = stamp.wrapping_add(self.clock_offset.load(Ordering::Relaxed));
+		let age = timestamp(now).wrapping_sub(local) as i32;
+
+		if age <= 0 {
+			return now;
+		}
+
+		now.checked_sub(Duration::from_micros(age as u64))
+			.unwrap_or(now)
+	}
+
+	/// Passes a packet from the other half on to whoever handles it.
+	async fn deliver(&self, packet: Packet) {
+		match packet {
+			Packet::UpdateBegin { .. }
+			| Packet::UpdateChunk { .. }
+			| Packet::UpdateAck(_)
+			| Packet::UpdateDone(_) => {
+				// Without the feature, nothing would take them out again.
+				if cfg!(feature = "split-update") {
+					self.updates.send(packet).await;
+				}
+			}
+			_ => self.incoming.send(Event::Packet(packet)).await,
+		}
+	}
+
+	fn try_reply(&self, reply: Reply) {
+		if self.replies.try_send(reply).is_err() {
+			count(&self.stats.dropped);
+		}
+	}
+
+	/// Hands a reply from the other half to the writer. Losing one only
+	/// costs a retransmission.
+	fn peer_reply(&self, reply: Reply) {
+		self.peer_replies.try_send(reply).ok();
+	}
+
+	async fn send_frame(&self, transport: &impl SplitTransport, frame: &[u8]) {
+		transport.send(frame).await;
+		count(&self.stats.frames_sent);
+	}
+
+	fn set_rate(&self, transport: &impl SplitTransport, rate: u8) {
+		self.rate.store(rate, Ordering::Relaxed);
+		transport.set_baud(RATES[rate as usize]);
+	}
+}
+
+#[derive(Clone)]
+pub enum Event {
+	Packet(Packet),
+	/// The other half started talking to us.
+	LinkUp,
+	/// The other half went quiet; anything it was holding down
+	/// should be released.
+	LinkDown,
+}
 
 #[derive(Clone)]
 pub enum Packet {
-	Down(u8, u8),
-	Up(u8, u8),
+	/// A key changed, at the given timestamp on the sender's clock.
+	Down(u8, u8, u32),
+	Up(u8, u8, u32),
 	EncoderCw,
 	EncoderCcw,
+	UsbSuspend(bool),
+	/// The full state of the sender's 30-key matrix, one bit per
+	/// key at `x + y * 6`.
+	Matrix(u32),
+	/// Whether the sender's USB port is enumerated by a host.
+	UsbConfigured(bool),
+	/// Sent on link-up. Its layout must never change, so that halves
+	/// running different firmware can still tell each other apart.
+	Hello {
+		version: u8,
+		build:   u32,
+	},
+	/// The master's state, for the other half to display.
+	State(SharedState),
+	/// Starts streaming a firmware image of the given size and CRC-32 to
+	/// the other half.
+	UpdateBegin {
+		size: u32,
+		crc:  u32,
+	},
+	UpdateChunk {
+		offset: u32,
+		len:    u8,
+		data:   [u8; UPDATE_CHUNK_SIZE],
+	},
+	/// Everything before the offset has been written to flash.
+	UpdateAck(u32),
+	/// The image was verified and will be booted, or it was rejected.
+	UpdateDone(bool),
 }
 
 impl Packet {
-	pub fn serialize(&self, buf: &mut [u8; PACKET_SIZE]) {
+	fn kind(&self) -> u8 {
 		match self {
-			Packet::Down(x, y) => {
-				buf[0] = 1;
-				buf[1] = *x;
-				buf[2] = *y;
+			Packet::Down(..) => 1,
+			Packet::Up(..) => 2,
+			Packet::EncoderCw => 5,
+			Packet::EncoderCcw => 6,
+			Packet::UsbSuspend(_) => 7,
+			Packet::Matrix(_) => 8,
+			Packet::UsbConfigured(_) => 9,
+			Packet::Hello { .. } => 10,
+			Packet::State(_) => 11,
+			Packet::UpdateBegin { .. } => 12,
+			Packet::UpdateChunk { .. } => 13,
+			Packet::UpdateAck(_) => 14,
+			Packet::UpdateDone(_) => 15,
+		}
+	}
+
+	/// Writes the packet's payload, returning its length.
+	fn serialize(&self, buf: &mut [u8; MAX_PAYLOAD]) -> usize {
+		match self {
+			Packet::Down(x, y, at) | Packet::Up(x, y, at) => {
+				buf[0] = *x;
+				buf[1] = *y;
+				buf[2..6].copy_from_slice(&at.to_le_bytes());
+				6
+			}
+			Packet::EncoderCw | Packet::EncoderCcw => 0,
+			Packet::UsbSuspend(suspended) => {
+				buf[0] = *suspended as u8;
+				1
+			}
+			Packet::UsbConfigured(configured) => {
+				buf[0] = *configured as u8;
+				1
+			}
+			Packet::Hello { version, build } => {
+				buf[0] = *version;
+				buf[1..5].copy_from_slice(&build.to_le_bytes());
+				5
+			}
+			Packet::State(state) => {
+				buf[0] = state.layers;
+				buf[1] = state.modifiers;
+				buf[2] = state.host_leds;
+				buf[3] = state.suspended as u8;
+				buf[4] = state.display.contrast;
+				buf[5] = state.display.flags();
+				buf[6] = state.debug_screen as u8;
+				7
+			}
+			Packet::Matrix(keys) => {
+				buf[..4].copy_from_slice(&keys.to_le_bytes());
+				4
+			}
+			Packet::UpdateBegin { size, crc } => {
+				buf[..4].copy_from_slice(&size.to_le_bytes());
+				buf[4..8].copy_from_slice(&crc.to_le_bytes());
+				8
 			}
-			Packet::Up(x, y) => {
-				buf[0] = 2;
-				buf[1] = *x;
-				buf[2] = *y;
+			Packet::UpdateChunk { offset, len, data } => {
+				let len = *len as usize;
+				buf[..4].copy_from_slice(&offset.to_le_bytes());
+				buf[4..4 + len].copy_from_slice(&data[..len]);
+				4 + len
 			}
-			Packet::EncoderCw => {
-				buf[0] = 5;
-				buf[1] = 0;
-				buf[2] = 0;
+			Packet::UpdateAck(offset) => {
+				buf[..4].copy_from_slice(&offset.to_le_bytes());
+				4
 			}
-			Packet::EncoderCcw => {
-				buf[0] = 6;
-				buf[1] = 0;
-				buf[2] = 0;
+			Packet::UpdateDone(ok) => {
+				buf[0] = *ok as u8;
+				1
 			}
 		}
 	}
 
-	pub fn deserialize(buf: [u8; PACKET_SIZE]) -> Option<Self> {
-		match buf[0] {
-			1 => Some(Packet::Down(buf[1], buf[2])),
-			2 => Some(Packet::Up(buf[1], buf[2])),
-			5 => Some(Packet::EncoderCw),
-			6 => Some(Packet::EncoderCcw),
+	fn deserialize(kind: u8, payload: &[u8]) -> Option<Self> {
+		match (kind, payload) {
+			(1, &[x, y, a, b, c, d]) => Some(Packet::Down(x, y, u32::from_le_bytes([a, b, c, d]))),
+			(2, &[x, y, a, b, c, d]) => Some(Packet::Up(x, y, u32::from_le_bytes([a, b, c, d]))),
+			(5, &[]) => Some(Packet::EncoderCw),
+			(6, &[]) => Some(Packet::EncoderCcw),
+			(7, &[suspended]) => Some(Packet::UsbSuspend(suspended != 0)),
+			(8, &[a, b, c, d]) => {
+				Some(Packet::Matrix(
+					u32::from_le_bytes([a, b, c, d]) & MATRIX_MASK,
+				))
+			}
+			(9, &[configured]) => Some(Packet::UsbConfigured(configured != 0)),
+			(10, &[version, a, b, c, d]) => {
+				Some(Packet::Hello {
+					version,
+					build: u32::from_le_bytes([a, b, c, d]),
+				})
+			}
+			(
+				11,
+				&[
+					layers,
+					modifiers,
+					host_leds,
+					suspended,
+					contrast,
+					flags,
+					debug_screen,
+				],
+			) => {
+				Some(Packet::State(SharedState {
+					layers,
+					modifiers,
+					host_leds,
+					suspended: suspended != 0,
+					display: DisplaySettings::from_flags(contrast, flags),
+					debug_screen: debug_screen != 0,
+				}))
+			}
+			(12, &[a, b, c, d, e, f, g, h]) => {
+				Some(Packet::UpdateBegin {
+					size: u32::from_le_bytes([a, b, c, d]),
+					crc:  u32::from_le_bytes([e, f, g, h]),
+				})
+			}
+			(13, &[a, b, c, d, ref chunk @ ..]) if chunk.len() <= UPDATE_CHUNK_SIZE => {
+				let mut data = [0; UPDATE_CHUNK_SIZE];
+				data[..chunk.len()].copy_from_slice(chunk);
+
+				Some(Packet::UpdateChunk {
+					offset: u32::from_le_bytes([a, b, c, d]),
+					len: chunk.len() as u8,
+					data,
+				})
+			}
+			(14, &[a, b, c, d]) => Some(Packet::UpdateAck(u32::from_le_bytes([a, b, c, d]))),
+			(15, &[ok]) => Some(Packet::UpdateDone(ok != 0)),
 			_ => None,
 		}
 	}
+
+	/// Frames the packet for the wire, returning the frame's length.
+	pub fn encode(
+		&self,
+		seq: u8,
+		retransmit: bool,
+		oldest: bool,
+		frame: &mut [u8; MAX_FRAME_SIZE],
+	) -> usize {
+		let mut payload = [0; MAX_PAYLOAD];
+		let len = self.serialize(&mut payload);
+
+		let mut kind = self.kind();
+		if retransmit {
+			kind |= RETRANSMIT;
+		}
+		if oldest {
+			kind |= OLDEST;
+		}
+
+		encode_frame(kind, seq, &payload[..len], frame)
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reply {
+	/// The frame with the given sequence number was received.
+	Ack(u8),
+	/// A corrupted frame was received; the sequence number is the one
+	/// expected next.
+	Nak(u8),
+	/// Proposes, echoes or confirms switching to a rate in `RATES`.
+	Rate(u8),
+	/// A link-quality test frame, echoed back by the right half.
+	Probe(u8),
+	/// Answers a ping sent at the first timestamp (on the other half's
+	/// clock), which arrived at the second (on ours).
+	Pong(u32, u32),
+}
+
+impl Reply {
+	fn encode(&self, frame: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+		match self {
+			Reply::Ack(seq) => encode_frame(KIND_ACK, *seq, &[], frame),
+			Reply::Nak(seq) => encode_frame(KIND_NAK, *seq, &[], frame),
+			Reply::Rate(rate) => encode_frame(KIND_RATE, 0, &[*rate], frame),
+			Reply::Probe(seq) => encode_frame(KIND_PROBE, *seq, &probe_pattern(*seq), frame),
+			Reply::Pong(sent, received) => {
+				let mut payload = [0; 12];
+				payload[..4].copy_from_slice(&sent.to_le_bytes());
+				payload[4..8].copy_from_slice(&received.to_le_bytes());
+				payload[8..].copy_from_slice(&timestamp(Instant::now()).to_le_bytes());
+				encode_frame(KIND_PONG, 0, &payload, frame)
+			}
+		}
+	}
 }
 
+pub enum Frame {
+	Data {
+		seq:        u8,
+		retransmit: bool,
+		oldest:     bool,
+		packet:     Packet,
+	},
+	Ack(u8),
+	Nak(u8),
+	Heartbeat,
+	/// The other half is done talking on the single-wire transport.
+	Turn,
+	Rate(u8),
+	Probe(u8),
+	Ping(u32),
+	/// The ping's timestamp, then when the other half received it and
+	/// when it answered, on its own clock.
+	Pong(u32, u32, u32),
+}
+
+/// Fills a probe with alternating bits and runs of ones and zeroes, which
+/// are the first to suffer when a rate is too fast for the cable.
+fn probe_pattern(seq: u8) -> [u8; MAX_PAYLOAD] {
+	core::array::from_fn(|i| {
+		match i % 4 {
+			0 => 0x55,
+			1 => 0xFF,
+			2 => 0x00,
+			_ => seq ^ i as u8,
+		}
+	})
+}
+
+fn read_u32(payload: &[u8], at: usize) -> u32 {
+	u32::from_le_bytes([
+		payload[at],
+		payload[at + 1],
+		payload[at + 2],
+		payload[at + 3],
+	])
+}
+
+/// Hands the line over to the other half on the single-wire transport.
+pub fn turn_frame(frame: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+	encode_frame(KIND_TURN, 0, &[], frame)
+}
+
+/// Makes sense of a frame's type, sequence number and payload.
+fn parse_frame(kind: u8, seq: u8, payload: &[u8]) -> Option<Frame> {
+	match kind {
+		KIND_ACK if payload.is_empty() => Some(Frame::Ack(seq)),
+		KIND_NAK if payload.is_empty() => Some(Frame::Nak(seq)),
+		KIND_HEARTBEAT if payload.is_empty() => Some(Frame::Heartbeat),
+		KIND_TURN if payload.is_empty() => Some(Frame::Turn),
+		KIND_RATE if payload.len() == 1 && (payload[0] as usize) < RATES.len() => {
+			Some(Frame::Rate(payload[0]))
+		}
+		KIND_PROBE if payload == probe_pattern(seq) => Some(Frame::Probe(seq)),
+		KIND_PING if payload.len() == 4 => Some(Frame::Ping(read_u32(payload, 0))),
+		KIND_PONG if payload.len() == 12 => {
+			Some(Frame::Pong(
+				read_u32(payload, 0),
+				read_u32(payload, 4),
+				read_u32(payload, 8),
+			))
+		}
+		_ => {
+			Packet::deserialize(kind & !(RETRANSMIT | OLDEST), payload).map(|packet| {
+				Frame::Data {
+					seq,
+					retransmit: kind & RETRANSMIT != 0,
+					oldest: kind & OLDEST != 0,
+					packet,
+				}
+			})
+		}
+	}
+}
+
+#[cfg(target_os = "none")]
 pub struct UartConfig {
 	pub pio0:  PIO0,
 	pub pin_1: PIN_1,
@@ -67,6 +641,7 @@ pub struct UartConfig {
 	pub side:  BoardSide,
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub async fn uart_task(config: UartConfig) -> ! {
 	let pio::Pio {
@@ -76,45 +651,902 @@ pub async fn uart_task(config: UartConfig) -> ! {
 		..
 	} = pio::Pio::new(config.pio0, crate::Irqs);
 
-	match config.side {
-		BoardSide::Left => {
-			let tx_program = PioUartTxProgram::new(&mut common);
-			let mut uart_tx = PioUartTx::new(BAUD, &mut common, sm0, config.pin_1, &tx_program);
+	// Both halves talk on `PIN_1`; `PIN_4` is left unused.
+	#[cfg(feature = "half-duplex")]
+	{
+		join(
+			half_duplex::run(&mut common, sm0, sm1, config.pin_1, RATES[0], config.side),
+			run_link(
+				&LINK,
+				&ChannelTransport::new(&half_duplex::TX, &half_duplex::RX),
+				config.side,
+			),
+		)
+		.await;
+
+		unreachable!();
+	}
+
+	#[cfg(not(feature = "half-duplex"))]
+	{
+		let transport = match config.side {
+			BoardSide::Left => {
+				PioTransport::new(&mut common, sm0, sm1, config.pin_1, config.pin_4, RATES[0])
+			}
+			BoardSide::Right => {
+				PioTransport::new(&mut common, sm0, sm1, config.pin_4, config.pin_1, RATES[0])
+			}
+		};
+
+		run_link(&LINK, &transport, config.side).await;
+	}
+}
+
+/// Runs both directions of `link` over `transport`. The left half leads
+/// rate negotiation; the right half follows.
+pub async fn run_link(link: &Link, transport: &impl SplitTransport, side: BoardSide) -> ! {
+	join(
+		uart_read(link, transport, side),
+		uart_write(link, transport, side),
+	)
+	.await;
+
+	unreachable!();
+}
+
+/// The low 32 bits of `at` in microseconds, as used on the wire.
+pub fn timestamp(at: Instant) -> u32 {
+	at.as_micros() as u32
+}
+
+/// A packet sent to the other half that has yet to be acknowledged.
+struct Unacked {
+	seq:      u8,
+	packet:   Packet,
+	/// When it's sent again, unless the acknowledgement arrives first.
+	deadline: Instant,
+	retries:  u8,
+}
 
-			let rx_program = PioUartRxProgram::new(&mut common);
-			let mut uart_rx = PioUartRx::new(BAUD, &mut common, sm1, config.pin_4, &rx_program);
+/// Delivers packets in the order they were sent, even when some of them
+/// had to be retransmitted, and drops the duplicates left over when an
+/// acknowledgement went missing.
+struct Reorder {
+	/// The sequence number of the next packet to deliver, once the first
+	/// one has arrived.
+	next:    Option<u8>,
+	/// Packets that arrived ahead of `next`, at their distance from it.
+	early:   [Option<Packet>; MAX_UNACKED],
+	/// When to stop waiting for `next` while there are early packets.
+	give_up: Option<Instant>,
+}
 
-			join(uart_read(&mut uart_rx), uart_write(&mut uart_tx)).await;
+impl Reorder {
+	fn new() -> Self {
+		Self {
+			next:    None,
+			early:   [const { None }; MAX_UNACKED],
+			give_up: None,
 		}
-		BoardSide::Right => {
-			let tx_program = PioUartTxProgram::new(&mut common);
-			let mut uart_tx = PioUartTx::new(BAUD, &mut common, sm0, config.pin_4, &tx_program);
+	}
+
+	/// Takes in a data frame, returning the packets that are now due.
+	fn accept(
+		&mut self,
+		seq: u8,
+		retransmit: bool,
+		oldest: bool,
+		packet: Packet,
+	) -> Vec<Packet, MAX_UNACKED> {
+		let mut due = Vec::new();
 
-			let rx_program = PioUartRxProgram::new(&mut common);
-			let mut uart_rx = PioUartRx::new(BAUD, &mut common, sm1, config.pin_1, &rx_program);
+		// The other half never gets further than this ahead of the oldest
+		// packet it's still retransmitting, so anything before `floor` was
+		// acknowledged or given up on.
+		let floor = if oldest {
+			seq
+		} else {
+			seq.wrapping_sub(MAX_UNACKED as u8 - 1)
+		};
+		let next = *self.next.get_or_insert(floor);
+		let behind = (seq.wrapping_sub(next) as i8) < 0;
+		let skip = floor.wrapping_sub(next) as i8;
 
-			join(uart_read(&mut uart_rx), uart_write(&mut uart_tx)).await;
+		if behind && retransmit {
+			// Delivered already; the acknowledgement must have been lost.
+			return due;
+		} else if behind || skip >= MAX_UNACKED as i8 {
+			// The other half started over, or moved on while we weren't
+			// listening.
+			due.extend(self.early.iter_mut().filter_map(Option::take));
+			self.next = Some(floor);
+		} else {
+			for _ in 0..skip.max(0) {
+				due.extend(self.early[0].take());
+				self.advance();
+			}
 		}
+
+		let ahead = self.next.map_or(0, |next| seq.wrapping_sub(next) as usize);
+		// Unless it's a retransmission of one that's already here.
+		self.early[ahead].get_or_insert(packet);
+
+		self.deliver(&mut due);
+		due
 	}
 
-	unreachable!();
+	/// Stops waiting for the missing packets, returning the ones after
+	/// them that are now due.
+	fn skip_missing(&mut self) -> Vec<Packet, MAX_UNACKED> {
+		let mut due = Vec::new();
+
+		if self.early.iter().any(Option::is_some) {
+			while self.early[0].is_none() {
+				self.advance();
+			}
+		}
+
+		self.deliver(&mut due);
+		due
+	}
+
+	fn deliver(&mut self, due: &mut Vec<Packet, MAX_UNACKED>) {
+		let delivered = due.len();
+
+		while let Some(packet) = self.early[0].take() {
+			due.push(packet).ok();
+			self.advance();
+		}
+
+		if self.early.iter().all(Option::is_none) {
+			self.give_up = None;
+		} else if self.give_up.is_none() || due.len() > delivered {
+			self.give_up = Some(Instant::now() + MISSING_TIMEOUT);
+		}
+	}
+
+	fn advance(&mut self) {
+		self.early.rotate_left(1);
+		self.next = self.next.map(|next| next.wrapping_add(1));
+	}
 }
 
-async fn uart_read<const S: usize>(uart_rx: &mut PioUartRx<'_, PIO0, S>) -> ! {
-	let mut buf = [0; PACKET_SIZE];
+async fn uart_read(link: &Link, transport: &impl SplitTransport, side: BoardSide) -> ! {
+	let mut decoder = Decoder::new();
+	let mut reorder = Reorder::new();
+	let mut last_seen = Instant::now();
+	// The round trip time of the ping `clock_offset` was measured from,
+	// and when.
+	let mut clock_sample: Option<(u32, Instant)> = None;
+
 	loop {
-		uart_rx.read_exact(&mut buf).await.unwrap();
-		if let Some(packet) = Packet::deserialize(buf) {
-			INCOMING.send(packet).await;
+		let link_up = link.is_up();
+
+		let deadline = if !decoder.is_empty() {
+			Instant::now() + IDLE_TIMEOUT
+		} else if link_up {
+			last_seen + LINK_TIMEOUT
+		} else {
+			Instant::MAX
+		};
+
+		let missing_deadline = reorder.give_up.unwrap_or(Instant::MAX);
+
+		match with_deadline(deadline.min(missing_deadline), transport.receive()).await {
+			Ok(byte) => decoder.push(byte),
+			Err(TimeoutError) if Instant::now() < deadline => {
+				for packet in reorder.skip_missing() {
+					link.deliver(packet).await;
+				}
+			}
+			Err(TimeoutError) => {
+				if !decoder.is_empty() {
+					count(&link.stats.resyncs);
+				}
+				decoder.flush();
+
+				if link_up && Instant::now() >= last_seen + LINK_TIMEOUT {
+					link.up.store(false, Ordering::Relaxed);
+					// A new session starts after the link comes back.
+					reorder = Reorder::new();
+					clock_sample = None;
+					link.clock_synced.store(false, Ordering::Relaxed);
+					// At a rate both halves are sure to agree on.
+					link.set_rate(transport, 0);
+					link.incoming.send(Event::LinkDown).await;
+				}
+			}
+		}
+
+		while let Some(result) = decoder.decode(parse_frame) {
+			if result.is_ok() {
+				count(&link.stats.frames_received);
+				last_seen = Instant::now();
+
+				if !link.is_up() {
+					link.up.store(true, Ordering::Relaxed);
+					link.incoming.send(Event::LinkUp).await;
+				}
+			}
+
+			match result {
+				Ok(Frame::Data {
+					seq,
+					retransmit,
+					oldest,
+					packet,
+				}) => {
+					link.try_reply(Reply::Ack(seq));
+
+					for packet in reorder.accept(seq, retransmit, oldest, packet) {
+						link.deliver(packet).await;
+					}
+				}
+				Ok(Frame::Ack(seq)) => link.peer_reply(Reply::Ack(seq)),
+				Ok(Frame::Nak(seq)) => link.peer_reply(Reply::Nak(seq)),
+				Ok(Frame::Heartbeat) => {}
+				#[cfg(all(target_os = "none", feature = "half-duplex"))]
+				Ok(Frame::Turn) => half_duplex::TURN.signal(()),
+				#[cfg(not(all(target_os = "none", feature = "half-duplex")))]
+				Ok(Frame::Turn) => {}
+				Ok(Frame::Rate(rate)) if side == BoardSide::Left => {
+					link.peer_reply(Reply::Rate(rate))
+				}
+				Ok(Frame::Rate(rate)) => link.try_reply(Reply::Rate(rate)),
+				Ok(Frame::Probe(seq)) if side == BoardSide::Left => {
+					link.peer_reply(Reply::Probe(seq))
+				}
+				Ok(Frame::Probe(seq)) => link.try_reply(Reply::Probe(seq)),
+				Ok(Frame::Ping(sent)) => {
+					link.try_reply(Reply::Pong(sent, timestamp(Instant::now())))
+				}
+				Ok(Frame::Pong(sent, received, answered)) => {
+					let now = Instant::now();
+					let returned = timestamp(now);
+
+					// The usual NTP estimate, which assumes the trip took as
+					// long both ways. That's most likely for the fastest one.
+					let rtt = returned
+						.wrapping_sub(sent)
+						.wrapping_sub(answered.wrapping_sub(received));
+					let there = sent.wrapping_sub(received);
+					let back = returned.wrapping_sub(answered);
+					let offset = there.wrapping_add((back.wrapping_sub(there) as i32 / 2) as u32);
+
+					let better = match clock_sample {
+						Some((best, at)) => rtt <= best || now >= at + CLOCK_SAMPLE_LIFETIME,
+						None => true,
+					};
+
+					if better {
+						clock_sample = Some((rtt, now));
+						link.clock_offset.store(offset, Ordering::Relaxed);
+						link.clock_synced.store(true, Ordering::Relaxed);
+					}
+				}
+				Err(DecodeError::Crc) => {
+					count(&link.stats.crc_errors);
+
+					if let Some(seq) = reorder.next {
+						link.try_reply(Reply::Nak(seq));
+					}
+				}
+				Err(DecodeError::Malformed) => count(&link.stats.resyncs),
+			}
+		}
+	}
+}
+
+async fn uart_write(link: &Link, transport: &impl SplitTransport, side: BoardSide) -> ! {
+	let mut frame = [0; MAX_FRAME_SIZE];
+	let mut seq: u8 = 0;
+	let mut unacked: Vec<Unacked, MAX_UNACKED> = Vec::new();
+	// A rate switch the left half has yet to confirm, as the deadline for
+	// the confirmation and the rate to fall back to.
+	let mut trial: Option<(Instant, u8)> = None;
+	// Rates from this one up recently failed or were too noisy.
+	let mut ceiling = RATES.len() as u8;
+	let mut ceiling_until = Instant::now();
+	let mut window_end = Instant::now() + ERROR_WINDOW;
+	let mut window_errors = link.stats.errors();
+	let mut next_ping = Instant::now();
+
+	loop {
+		let now = Instant::now();
+		if now >= next_ping && link.is_up() && !link.is_held() {
+			next_ping = now + PING_INTERVAL;
+
+			let len = encode_frame(KIND_PING, 0, &timestamp(now).to_le_bytes(), &mut frame);
+			link.send_frame(transport, &frame[..len]).await;
+		}
+
+		if side == BoardSide::Left && now >= window_end {
+			window_end = now + ERROR_WINDOW;
+
+			let rate = link.rate.load(Ordering::Relaxed);
+			let errors = link.stats.errors().wrapping_sub(window_errors);
+			window_errors = link.stats.errors();
+			if errors > MAX_ERRORS && rate > 0 && link.is_up() && !link.is_held() {
+				ceiling = rate;
+				ceiling_until = now + RATE_BACKOFF;
+				// If even that fails, fall back to the rate the other half
+				// ends up at once it loses the link.
+				if !negotiate(link, transport, &mut frame, rate - 1).await {
+					link.set_rate(transport, 0);
+				}
+			}
+		}
+
+		let mut idle_deadline = Instant::now() + HEARTBEAT_INTERVAL;
+		if let Some((trial_deadline, _)) = trial {
+			idle_deadline = idle_deadline.min(trial_deadline);
+		}
+		let deadline = unacked
+			.iter()
+			.map(|frame| frame.deadline)
+			.fold(idle_deadline, Instant::min);
+
+		// The other half can only hold on to so many packets while it waits
+		// for the oldest to be retransmitted.
+		let window_full = unacked
+			.first()
+			.is_some_and(|oldest| seq.wrapping_sub(oldest.seq) as usize + 1 >= MAX_UNACKED);
+		let next_packet = async {
+			if window_full {
+				core::future::pending::<()>().await;
+			}
+			loop {
+				link.outgoing.ready_to_receive().await;
+
+				if link.is_held() {
+					link.released.wait().await;
+				} else if let Ok(packet) = link.outgoing.try_receive() {
+					break packet;
+				}
+			}
+		};
+
+		let packet = match select4(
+			link.replies.receive(),
+			link.peer_replies.receive(),
+			next_packet,
+			Timer::at(deadline),
+		)
+		.await
+		{
+			Either4::First(reply) => {
+				send_reply(link, transport, reply, &mut frame, &mut trial).await;
+				continue;
+			}
+			Either4::Second(Reply::Ack(acked)) => {
+				unacked.retain(|frame| frame.seq != acked);
+				continue;
+			}
+			Either4::Second(Reply::Nak(missing)) => {
+				// Retransmit right away.
+				if let Some(frame) = unacked.iter_mut().find(|frame| frame.seq == missing) {
+					frame.deadline = Instant::now();
+				}
+				continue;
+			}
+			// Left over from a rate negotiation.
+			Either4::Second(_) => continue,
+			Either4::Third(packet) => packet,
+			Either4::Fourth(()) => {
+				if retransmit_due(link, transport, &mut unacked, &mut frame).await
+					|| Instant::now() < idle_deadline
+					|| link.is_held()
+				{
+					continue;
+				}
+
+				if let Some((trial_deadline, previous)) = trial {
+					if Instant::now() >= trial_deadline {
+						// The left half gave up on it.
+						trial = None;
+						link.set_rate(transport, previous);
+						continue;
+					}
+				}
+
+				if Instant::now() >= ceiling_until {
+					ceiling = RATES.len() as u8;
+				}
+
+				// Only try a faster rate while there's nothing else to send.
+				let rate = link.rate.load(Ordering::Relaxed);
+				if side == BoardSide::Left
+					&& link.is_up() && unacked.is_empty()
+					&& rate + 1 < ceiling
+				{
+					if !negotiate(link, transport, &mut frame, rate + 1).await {
+						ceiling = rate + 1;
+						ceiling_until = Instant::now() + RATE_BACKOFF;
+					}
+					continue;
+				}
+
+				let len = encode_frame(KIND_HEARTBEAT, 0, &[], &mut frame);
+				link.send_frame(transport, &frame[..len]).await;
+				continue;
+			}
+		};
+
+		seq = seq.wrapping_add(1);
+
+		let len = packet.encode(seq, false, unacked.is_empty(), &mut frame);
+		link.send_frame(transport, &frame[..len]).await;
+
+		// Nobody's listening; it was only sent in case the link is just
+		// coming up.
+		if link.is_up() {
+			unacked
+				.push(Unacked {
+					seq,
+					packet,
+					deadline: Instant::now() + ACK_TIMEOUT,
+					retries: 0,
+				})
+				.ok();
+		}
+	}
+}
+
+/// Retransmits the packets whose acknowledgement is overdue, dropping
+/// those that have run out of retries. Returns whether anything was sent.
+async fn retransmit_due(
+	link: &Link,
+	transport: &impl SplitTransport,
+	unacked: &mut Vec<Unacked, MAX_UNACKED>,
+	frame: &mut [u8; MAX_FRAME_SIZE],
+) -> bool {
+	let mut sent = false;
+	let mut i = 0;
+
+	while i < unacked.len() {
+		let pending = &mut unacked[i];
+
+		if Instant::now() < pending.deadline {
+			i += 1;
+		} else if pending.retries == MAX_RETRIES {
+			count(&link.stats.dropped);
+			unacked.remove(i);
+		} else {
+			pending.retries += 1;
+			count(&link.stats.retransmissions);
+
+			let len = pending.packet.encode(pending.seq, true, i == 0, frame);
+			link.send_frame(transport, &frame[..len]).await;
+
+			unacked[i].deadline = Instant::now() + ACK_TIMEOUT;
+			sent = true;
+			i += 1;
 		}
 	}
+
+	sent
 }
 
-async fn uart_write<const S: usize>(uart_rx: &mut PioUartTx<'_, PIO0, S>) -> ! {
-	let mut buf = [0; PACKET_SIZE];
+/// Sends a reply to the other half. A proposed rate is echoed at the old
+/// rate before switching, and stays on trial until the left half confirms
+/// it by proposing it again.
+async fn send_reply(
+	link: &Link,
+	transport: &impl SplitTransport,
+	reply: Reply,
+	frame: &mut [u8; MAX_FRAME_SIZE],
+	trial: &mut Option<(Instant, u8)>,
+) {
+	match reply {
+		Reply::Rate(rate) if trial.is_some() && rate == link.rate.load(Ordering::Relaxed) => {
+			*trial = None;
+			return;
+		}
+		Reply::Probe(_) => {
+			// The test is still going.
+			if let Some((deadline, _)) = trial {
+				*deadline = Instant::now() + TRIAL_TIMEOUT;
+			}
+		}
+		_ => {}
+	}
+
+	let len = reply.encode(frame);
+	link.send_frame(transport, &frame[..len]).await;
+
+	if let Reply::Rate(rate) = reply {
+		let previous = match *trial {
+			Some((_, previous)) => previous,
+			None => link.rate.load(Ordering::Relaxed),
+		};
+
+		Timer::after(SWITCH_GUARD).await;
+		link.set_rate(transport, rate);
+		*trial = Some((Instant::now() + TRIAL_TIMEOUT, previous));
+	}
+}
+
+/// Switches both halves to `rate` and tests the link there, going back to
+/// the old rate if any probe goes unanswered.
+async fn negotiate(
+	link: &Link,
+	transport: &impl SplitTransport,
+	frame: &mut [u8; MAX_FRAME_SIZE],
+	rate: u8,
+) -> bool {
+	let previous = link.rate.load(Ordering::Relaxed);
+
+	link.peer_replies.clear();
+	let len = Reply::Rate(rate).encode(frame);
+	link.send_frame(transport, &frame[..len]).await;
+
+	if !wait_peer_reply(link, Reply::Rate(rate)).await {
+		return false;
+	}
+
+	Timer::after(SWITCH_GUARD).await;
+	link.set_rate(transport, rate);
+
+	for seq in 0..PROBE_COUNT {
+		let len = Reply::Probe(seq).encode(frame);
+		link.send_frame(transport, &frame[..len]).await;
+
+		if !wait_peer_reply(link, Reply::Probe(seq)).await {
+			link.set_rate(transport, previous);
+			return false;
+		}
+	}
+
+	let len = Reply::Rate(rate).encode(frame);
+	link.send_frame(transport, &frame[..len]).await;
+
+	true
+}
+
+async fn wait_peer_reply(link: &Link, expected: Reply) -> bool {
+	let deadline = Instant::now() + PROBE_TIMEOUT;
+
 	loop {
-		let packet = OUTGOING.receive().await;
-		packet.serialize(&mut buf);
-		uart_rx.write_all(&buf).await.unwrap();
+		match with_deadline(deadline, link.peer_replies.receive()).await {
+			Ok(reply) if reply == expected => return true,
+			Ok(_) => {}
+			Err(TimeoutError) => return false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use core::{cell::Cell, future::Future};
+
+	use embassy_futures::{block_on, select::select};
+	use embassy_sync::pipe::Pipe;
+	use embassy_time::with_timeout;
+
+	use super::*;
+	use crate::transport::ChannelTransport;
+
+	const TIMEOUT: Duration = Duration::from_secs(2);
+
+	/// Loses every `every`th frame sent through it (none if it's 0), or
+	/// all of them while it's cut.
+	struct Lossy {
+		inner: ChannelTransport<'static, 256>,
+		every: usize,
+		sent:  Cell<usize>,
+		cut:   Cell<bool>,
+	}
+
+	impl SplitTransport for Lossy {
+		async fn send(&self, frame: &[u8]) {
+			let sent = self.sent.get() + 1;
+			self.sent.set(sent);
+
+			if !self.cut.get() && sent.checked_rem(self.every) != Some(0) {
+				self.inner.send(frame).await;
+			}
+		}
+
+		async fn receive(&self) -> u8 {
+			self.inner.receive().await
+		}
+	}
+
+	struct Halves {
+		left: Link,
+		right: Link,
+		left_transport: Lossy,
+		right_transport: Lossy,
+	}
+
+	fn halves(every: usize) -> &'static Halves {
+		let pipe = || &*Box::leak(Box::new(Pipe::new()));
+		let (left, right) = ChannelTransport::pair(pipe(), pipe());
+		let lossy = |inner| {
+			Lossy {
+				inner,
+				every,
+				sent: Cell::new(0),
+				cut: Cell::new(false),
+			}
+		};
+
+		Box::leak(Box::new(Halves {
+			left: Link::new(),
+			right: Link::new(),
+			left_transport: lossy(left),
+			right_transport: lossy(right),
+		}))
+	}
+
+	/// Runs both ends of the link until `test` is done with them.
+	fn run(halves: &'static Halves, test: impl Future<Output = ()>) {
+		block_on(select(
+			join(
+				run_link(&halves.left, &halves.left_transport, BoardSide::Left),
+				run_link(&halves.right, &halves.right_transport, BoardSide::Right),
+			),
+			test,
+		));
+	}
+
+	async fn next_event(link: &Link) -> Event {
+		with_timeout(TIMEOUT, link.incoming.receive())
+			.await
+			.expect("no event from the link")
+	}
+
+	async fn link_up(halves: &Halves) {
+		assert!(matches!(next_event(&halves.left).await, Event::LinkUp));
+		assert!(matches!(next_event(&halves.right).await, Event::LinkUp));
+	}
+
+	#[test]
+	fn comes_up_at_the_fastest_rate() {
+		let halves = halves(0);
+
+		run(halves, async {
+			link_up(halves).await;
+
+			let fastest = RATES[RATES.len() - 1];
+			with_timeout(TIMEOUT, async {
+				while halves.left.baud() != fastest || halves.right.baud() != fastest {
+					Timer::after_millis(10).await;
+				}
+			})
+			.await
+			.expect("the rate was never raised");
+
+			halves.left.try_send(Packet::Matrix(0x1234));
+			assert!(matches!(
+				next_event(&halves.right).await,
+				Event::Packet(Packet::Matrix(0x1234))
+			));
+		});
+	}
+
+	#[test]
+	fn retransmits_lost_frames() {
+		let halves = halves(5);
+
+		run(halves, async {
+			link_up(halves).await;
+
+			for keys in 0..32 {
+				halves.left.try_send(Packet::Matrix(keys));
+			}
+
+			// Each exactly once and in order, even when only the ACK was lost.
+			for keys in 0..32 {
+				match next_event(&halves.right).await {
+					Event::Packet(Packet::Matrix(received)) => assert_eq!(received, keys),
+					_ => panic!("expected matrix {keys}"),
+				}
+			}
+
+			let stats = &halves.left.stats;
+			assert!(stats.retransmissions.load(Ordering::Relaxed) > 0);
+			assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+		});
+	}
+
+	#[test]
+	fn skips_a_packet_that_never_got_through() {
+		let halves = halves(0);
+
+		run(halves, async {
+			link_up(halves).await;
+
+			halves.left.try_send(Packet::Matrix(1));
+			assert!(matches!(
+				next_event(&halves.right).await,
+				Event::Packet(Packet::Matrix(1))
+			));
+
+			halves.left_transport.cut.set(true);
+			halves.left.try_send(Packet::Matrix(2));
+			Timer::after(MISSING_TIMEOUT).await;
+			halves.left_transport.cut.set(false);
+
+			halves.left.try_send(Packet::Matrix(3));
+			assert!(matches!(
+				next_event(&halves.right).await,
+				Event::Packet(Packet::Matrix(3))
+			));
+			assert_eq!(halves.left.stats.dropped.load(Ordering::Relaxed), 1);
+		});
+	}
+
+	#[test]
+	fn stays_quiet_while_held() {
+		let halves = halves(0);
+
+		run(halves, async {
+			link_up(halves).await;
+
+			halves.left.hold();
+			halves.left.try_send(Packet::Matrix(1));
+			assert!(
+				with_timeout(HEARTBEAT_INTERVAL * 2, halves.right.incoming.receive())
+					.await
+					.is_err()
+			);
+
+			halves.left.release();
+			assert!(matches!(
+				next_event(&halves.right).await,
+				Event::Packet(Packet::Matrix(1))
+			));
+		});
+	}
+
+	#[test]
+	fn goes_down_when_the_other_half_goes_quiet() {
+		let halves = halves(0);
+
+		run(halves, async {
+			link_up(halves).await;
+
+			halves.right_transport.cut.set(true);
+			assert!(matches!(next_event(&halves.left).await, Event::LinkDown));
+			assert!(!halves.left.is_up());
+			assert_eq!(halves.left.baud(), RATES[0]);
+
+			halves.right_transport.cut.set(false);
+			assert!(matches!(next_event(&halves.left).await, Event::LinkUp));
+		});
+	}
+
+	fn matrices(packets: Vec<Packet, MAX_UNACKED>) -> std::vec::Vec<u32> {
+		packets
+			.into_iter()
+			.map(|packet| {
+				match packet {
+					Packet::Matrix(keys) => keys,
+					_ => unreachable!(),
+				}
+			})
+			.collect()
+	}
+
+	#[test]
+	fn reorders_around_a_retransmission() {
+		let mut reorder = Reorder::new();
+
+		assert_eq!(
+			matrices(reorder.accept(7, false, true, Packet::Matrix(7))),
+			[7]
+		);
+		assert_eq!(
+			matrices(reorder.accept(9, false, false, Packet::Matrix(9))),
+			[]
+		);
+		assert_eq!(
+			matrices(reorder.accept(10, false, false, Packet::Matrix(10))),
+			[]
+		);
+		assert_eq!(
+			matrices(reorder.accept(8, true, true, Packet::Matrix(8))),
+			[8, 9, 10]
+		);
+		assert_eq!(reorder.give_up, None);
+	}
+
+	#[test]
+	fn drops_duplicates() {
+		let mut reorder = Reorder::new();
+
+		reorder.accept(1, false, true, Packet::Matrix(1));
+		reorder.accept(2, false, true, Packet::Matrix(2));
+		assert_eq!(
+			matrices(reorder.accept(1, true, true, Packet::Matrix(1))),
+			[]
+		);
+		assert_eq!(
+			matrices(reorder.accept(2, true, true, Packet::Matrix(2))),
+			[]
+		);
+
+		reorder.accept(4, false, false, Packet::Matrix(4));
+		assert_eq!(
+			matrices(reorder.accept(4, true, false, Packet::Matrix(4))),
+			[]
+		);
+		assert_eq!(
+			matrices(reorder.accept(3, true, true, Packet::Matrix(3))),
+			[3, 4]
+		);
+	}
+
+	#[test]
+	fn waits_for_packets_sent_before_the_first() {
+		let mut reorder = Reorder::new();
+
+		assert_eq!(
+			matrices(reorder.accept(2, false, false, Packet::Matrix(2))),
+			[]
+		);
+		assert_eq!(
+			matrices(reorder.accept(1, true, true, Packet::Matrix(1))),
+			[1, 2]
+		);
+	}
+
+	#[test]
+	fn waits_for_a_lost_first_packet_while_more_arrive() {
+		let mut reorder = Reorder::new();
+
+		for seq in 2..5 {
+			assert_eq!(
+				matrices(reorder.accept(seq, false, false, Packet::Matrix(seq as u32))),
+				[]
+			);
+		}
+		assert_eq!(
+			matrices(reorder.accept(1, true, true, Packet::Matrix(1))),
+			[1, 2, 3, 4]
+		);
+	}
+
+	#[test]
+	fn skips_what_the_other_half_gave_up_on() {
+		let mut reorder = Reorder::new();
+
+		reorder.accept(1, false, true, Packet::Matrix(1));
+		reorder.accept(3, false, false, Packet::Matrix(3));
+		assert_eq!(
+			matrices(reorder.accept(4, true, true, Packet::Matrix(4))),
+			[3, 4]
+		);
+		assert_eq!(reorder.next, Some(5));
+	}
+
+	#[test]
+	fn skips_missing_packets_in_time() {
+		let mut reorder = Reorder::new();
+
+		reorder.accept(255, false, true, Packet::Matrix(255));
+		reorder.accept(1, false, false, Packet::Matrix(1));
+		reorder.accept(2, false, false, Packet::Matrix(2));
+		assert!(reorder.give_up.is_some());
+
+		assert_eq!(matrices(reorder.skip_missing()), [1, 2]);
+		assert_eq!(reorder.next, Some(3));
+		assert_eq!(reorder.give_up, None);
+	}
+
+	#[test]
+	fn starts_over_with_the_other_half() {
+		let mut reorder = Reorder::new();
+
+		reorder.accept(100, false, true, Packet::Matrix(100));
+		reorder.accept(102, false, false, Packet::Matrix(102));
+
+		// Without the retransmit flag, this can't be a duplicate.
+		assert_eq!(
+			matrices(reorder.accept(1, false, true, Packet::Matrix(1))),
+			[102, 1]
+		);
+		assert_eq!(reorder.next, Some(2));
 	}
 }
@@ -0,0 +1,320 @@
+//! Updating the other half's firmware over the split link.
+//!
+//! The host writes the other half's image to the update interface of the
+//! USB-connected half, which streams it over the link a flash page at a
+//! time. The receiving half stages it in the DFU partition, checks its
+//! CRC-32 and reboots, after which embassy-boot's bootloader swaps it in.
+//! A new image that never gets as far as `update_task` is rolled back.
+//!
+//! `scripts/update.py` is the host's side (see `Makefile.toml`), and
+//! `bootloader/` the bootloader.
+
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_deadline};
+use embedded_storage::nor_flash::ReadNorFlash;
+use portable_atomic::{AtomicU8, AtomicU32, Ordering};
+
+use crate::{
+	settings::SharedFlash,
+	uart::{self, Packet, UPDATE_CHUNK_SIZE},
+};
+
+/// Output reports from the host's update tool.
+pub static FROM_HOST: Channel<CriticalSectionRawMutex, [u8; 64], 4> = Channel::new();
+
+/// Report commands: begin (size and CRC-32 as little-endian `u32`s),
+/// and data (length, then up to 62 bytes of the image).
+const CMD_BEGIN: u8 = 0x01;
+const CMD_DATA: u8 = 0x02;
+
+pub const STATE_IDLE: u8 = 0;
+pub const STATE_SENDING: u8 = 1;
+pub const STATE_VERIFYING: u8 = 2;
+pub const STATE_DONE: u8 = 3;
+pub const STATE_FAILED: u8 = 4;
+
+const PAGE_SIZE: usize = 4096;
+
+/// How long the receiver may take to write a page.
+const PAGE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the receiver waits before writing a page, for the link to
+/// acknowledge the chunk that completed it. The sender holds the link
+/// from then on, so nothing arrives while the receiver can't listen.
+const WRITE_GUARD: Duration = Duration::from_millis(5);
+/// How long the receiver may take to check the whole image.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the final acknowledgement to go out before
+/// rebooting into the bootloader.
+const REBOOT_DELAY: Duration = Duration::from_millis(100);
+
+/// How far the update sent from this half has got.
+static STATE: AtomicU8 = AtomicU8::new(STATE_IDLE);
+/// The image bytes the other half has written so far.
+static PROGRESS: AtomicU32 = AtomicU32::new(0);
+/// Counts the updates begun, so that the host can tell when `STATE`
+/// stops describing the one before.
+static ATTEMPT: AtomicU8 = AtomicU8::new(0);
+
+/// The update state, attempt and progress, as read by the host with
+/// GET_REPORT.
+pub fn status() -> [u8; 6] {
+	let mut status = [0; 6];
+	status[0] = STATE.load(Ordering::Relaxed);
+	status[1] = ATTEMPT.load(Ordering::Relaxed);
+	status[2..].copy_from_slice(&PROGRESS.load(Ordering::Relaxed).to_le_bytes());
+	status
+}
+
+/// Why an update from this half didn't get through.
+enum Abort {
+	Failed,
+	/// The host began another update, with this size and CRC-32.
+	Restarted(u32, u32),
+}
+
+pub struct UpdateConfig {
+	pub flash: &'static SharedFlash,
+}
+
+/// An image being received from the other half.
+struct Staging {
+	size:     u32,
+	crc:      u32,
+	received: u32,
+	page:     [u8; PAGE_SIZE],
+}
+
+#[embassy_executor::task]
+pub async fn update_task(config: UpdateConfig) -> ! {
+	let flash = config.flash;
+
+	let mut dfu = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash).dfu;
+	let mut aligned = AlignedBuffer([0; 1]);
+	let mut updater = BlockingFirmwareUpdater::new(
+		FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash),
+		&mut aligned.0,
+	);
+
+	// Got this far, so keep this firmware.
+	updater.mark_booted().ok();
+
+	let mut staging: Option<Staging> = None;
+
+	loop {
+		match select(FROM_HOST.receive(), uart::LINK.updates.receive()).await {
+			Either::First(report) => {
+				let mut begin = parse_begin(&report);
+
+				while let Some((size, crc)) = begin.take() {
+					STATE.store(STATE_SENDING, Ordering::Relaxed);
+					PROGRESS.store(0, Ordering::Relaxed);
+					ATTEMPT.fetch_add(1, Ordering::Relaxed);
+
+					let state = match send_image(size, crc).await {
+						Ok(()) => STATE_DONE,
+						Err(Abort::Failed) => STATE_FAILED,
+						Err(Abort::Restarted(size, crc)) => {
+							begin = Some((size, crc));
+							continue;
+						}
+					};
+					STATE.store(state, Ordering::Relaxed);
+				}
+			}
+			Either::Second(Packet::UpdateBegin { size, crc }) => {
+				if size as usize > dfu.capacity() {
+					staging = None;
+					uart::LINK.try_send(Packet::UpdateDone(false));
+				} else {
+					staging = Some(Staging {
+						size,
+						crc,
+						received: 0,
+						page: [0xFF; PAGE_SIZE],
+					});
+				}
+			}
+			Either::Second(Packet::UpdateChunk { offset, len, data }) => {
+				let Some(image) = &mut staging else {
+					continue;
+				};
+
+				// A chunk went missing; the sender has to start over.
+				if offset != image.received || offset + len as u32 > image.size {
+					staging = None;
+					uart::LINK.try_send(Packet::UpdateDone(false));
+					continue;
+				}
+
+				let mut ok = true;
+
+				for &byte in &data[..len as usize] {
+					image.page[image.received as usize % PAGE_SIZE] = byte;
+					image.received += 1;
+
+					let page_full = image.received as usize % PAGE_SIZE == 0;
+					if page_full || image.received == image.size {
+						let start = (image.received - 1) as usize / PAGE_SIZE * PAGE_SIZE;
+						Timer::after(WRITE_GUARD).await;
+						ok &= updater.write_firmware(start, &image.page).is_ok();
+						image.page.fill(0xFF);
+
+						uart::LINK
+							.outgoing
+							.send(Packet::UpdateAck(image.received))
+							.await;
+					}
+				}
+
+				if !ok {
+					staging = None;
+					uart::LINK.try_send(Packet::UpdateDone(false));
+				} else if image.received == image.size {
+					let verified = image.crc == image_crc(&mut dfu, image.size);
+					let ok = verified && updater.mark_updated().is_ok();
+					staging = None;
+
+					uart::LINK.outgoing.send(Packet::UpdateDone(ok)).await;
+
+					if ok {
+						Timer::after(REBOOT_DELAY).await;
+						cortex_m::peripheral::SCB::sys_reset();
+					}
+				}
+			}
+			Either::Second(_) => {}
+		}
+	}
+}
+
+/// The size and CRC-32 of the image, if `report` begins an update.
+fn parse_begin(report: &[u8; 64]) -> Option<(u32, u32)> {
+	(report[0] == CMD_BEGIN).then(|| {
+		let size = u32::from_le_bytes([report[1], report[2], report[3], report[4]]);
+		let crc = u32::from_le_bytes([report[5], report[6], report[7], report[8]]);
+		(size, crc)
+	})
+}
+
+/// Streams an image from the host to the other half, a page at a time.
+async fn send_image(size: u32, crc: u32) -> Result<(), Abort> {
+	if !uart::LINK.is_up() {
+		return Err(Abort::Failed);
+	}
+
+	// Anything left over from an earlier attempt.
+	while uart::LINK.updates.try_receive().is_ok() {}
+
+	uart::LINK
+		.outgoing
+		.send(Packet::UpdateBegin { size, crc })
+		.await;
+
+	let mut sent = 0;
+	let mut data = [0; UPDATE_CHUNK_SIZE];
+	let mut len = 0;
+
+	while sent < size {
+		let report = FROM_HOST.receive().await;
+
+		if let Some((size, crc)) = parse_begin(&report) {
+			return Err(Abort::Restarted(size, crc));
+		}
+		if report[0] != CMD_DATA {
+			continue;
+		}
+
+		let count = (report[1] as usize).min(report.len() - 2);
+
+		for &byte in &report[2..2 + count] {
+			if sent == size {
+				break;
+			}
+
+			data[len] = byte;
+			len += 1;
+			sent += 1;
+
+			if len < UPDATE_CHUNK_SIZE && sent < size {
+				continue;
+			}
+
+			let offset = sent - len as u32;
+			uart::LINK
+				.outgoing
+				.send(Packet::UpdateChunk {
+					offset,
+					len: len as u8,
+					data,
+				})
+				.await;
+			len = 0;
+
+			// Give the other half time to write each page it completes,
+			// keeping quiet since it can't listen to the link while it does.
+			let page_end = (offset as usize / PAGE_SIZE + 1) * PAGE_SIZE;
+			if sent as usize >= page_end || sent == size {
+				uart::LINK.hold();
+				let acked = wait_ack(sent.min(page_end as u32)).await;
+				uart::LINK.release();
+
+				acked?;
+				PROGRESS.store(sent, Ordering::Relaxed);
+			}
+		}
+	}
+
+	STATE.store(STATE_VERIFYING, Ordering::Relaxed);
+
+	match with_deadline(Instant::now() + VERIFY_TIMEOUT, wait_done()).await {
+		Ok(true) => Ok(()),
+		_ => Err(Abort::Failed),
+	}
+}
+
+async fn wait_ack(offset: u32) -> Result<(), Abort> {
+	let deadline = Instant::now() + PAGE_TIMEOUT;
+
+	loop {
+		match with_deadline(deadline, uart::LINK.updates.receive()).await {
+			Ok(Packet::UpdateAck(acked)) if acked >= offset => return Ok(()),
+			Ok(Packet::UpdateDone(_)) | Err(TimeoutError) => return Err(Abort::Failed),
+			Ok(_) => {}
+		}
+	}
+}
+
+async fn wait_done() -> bool {
+	loop {
+		if let Packet::UpdateDone(ok) = uart::LINK.updates.receive().await {
+			return ok;
+		}
+	}
+}
+
+/// The CRC-32 (as used by zlib) of the first `size` bytes staged in `dfu`.
+fn image_crc(dfu: &mut impl ReadNorFlash, size: u32) -> u32 {
+	let mut crc = !0_u32;
+	let mut buf = [0; 256];
+	let mut offset = 0;
+
+	while offset < size {
+		let len = (size - offset).min(buf.len() as u32) as usize;
+		if dfu.read(offset, &mut buf[..len]).is_err() {
+			return !crc;
+		}
+
+		for &byte in &buf[..len] {
+			crc ^= byte as u32;
+			for _ in 0..8 {
+				crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+			}
+		}
+
+		offset += len as u32;
+	}
+
+	!crc
+}
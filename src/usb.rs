@@ -1,20 +1,159 @@
-use core::sync::atomic::{AtomicBool, Ordering};
-
-use embassy_futures::join::join;
+use embassy_futures::{
+	join::join5,
+	select::{Either, select},
+};
 use embassy_rp::{peripherals::USB, usb::Driver};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_sync::{
+	blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+#[cfg(feature = "split-update")]
+use embassy_usb::class::hid::HidReaderWriter;
 use embassy_usb::{
 	Builder, Config, Handler,
-	class::hid::{HidWriter, State},
+	class::hid::{HidWriter, ReportId, RequestHandler, State},
+	control::{InResponse, OutResponse, Recipient, Request, RequestType},
+	driver::{Endpoint as _, EndpointIn, EndpointOut},
 };
-use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport, SerializedDescriptor};
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+use usbd_hid::descriptor::{
+	KeyboardReport, SerializedDescriptor, SystemControlReport, generator_prelude::*,
+};
+
+use crate::{led, state, uart};
 
 pub static OUTGOING: Channel<CriticalSectionRawMutex, Event, 32> = Channel::new();
 
+/// Whether the host has suspended the bus, either on this half
+/// or (as forwarded over the split link) on the other one.
+pub static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_BOOT: u8 = 0x01;
+const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_HID: u8 = 0x21;
+const HID_DESC_REPORT: u8 = 0x22;
+
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+/// How often the link stats are sent as an input report.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the host selected the boot protocol (rather than the
+/// default report protocol) on each of our HID interfaces.
+static KEYBOARD_BOOT: AtomicBool = AtomicBool::new(false);
+static CONSUMER_BOOT: AtomicBool = AtomicBool::new(false);
+static SYSTEM_BOOT: AtomicBool = AtomicBool::new(false);
+
+/// The keyboard LEDs last set by the host (bit 0 is Num Lock, bit 1 Caps Lock).
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Whether a host has enumerated the USB port on this half.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the bus attached to *this* half is suspended.
+static BUS_SUSPENDED: AtomicBool = AtomicBool::new(false);
+static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Updates the keyboard-wide suspend state, blanking the LED on suspend.
+pub fn set_suspended(suspended: bool) {
+	SUSPENDED.store(suspended, Ordering::Relaxed);
+
+	if suspended {
+		led::LED_STATE.signal(led::LedState::Off);
+	}
+
+	state::DIRTY.signal(());
+}
+
+pub fn host_leds() -> u8 {
+	HOST_LEDS.load(Ordering::Relaxed)
+}
+
+pub fn is_configured() -> bool {
+	CONFIGURED.load(Ordering::Relaxed)
+}
+
+/// Records whether a host has enumerated us, telling the other half
+/// whenever it changes.
+fn set_configured(configured: bool) {
+	if CONFIGURED.swap(configured, Ordering::Relaxed) != configured {
+		uart::LINK.try_send(uart::Packet::UsbConfigured(configured));
+	}
+}
+
+/// Asks the host to resume if it suspended the bus attached to this half.
+pub fn wake_host() {
+	if BUS_SUSPENDED.load(Ordering::Relaxed) {
+		REMOTE_WAKEUP.signal(());
+	}
+}
+
 #[derive(Clone)]
 pub enum Event {
 	Update([u8; 6], u8),
+	/// Presses and immediately releases a consumer usage.
 	Consumer(u16),
+	ConsumerDown(u16),
+	ConsumerUp(u16),
+	/// Presses a System Control usage (e.g. `0x82` for System Sleep).
+	SystemDown(u8),
+	SystemUp(u8),
+}
+
+/// Like `MediaKeyboardReport`, but with room for several
+/// simultaneously held usages.
+#[gen_hid_descriptor(
+	(collection = APPLICATION, usage_page = CONSUMER, usage = CONSUMER_CONTROL) = {
+		(usage_page = CONSUMER, usage_min = 0x00, usage_max = 0x514) = {
+			#[item_settings data,array,absolute,not_null] usage_ids=input;
+		};
+	}
+)]
+pub struct ConsumerReport {
+	pub usage_ids: [u16; 4],
+}
+
+/// Vendor-defined report carrying `uart::LINK.stats` as little-endian `u32`s,
+/// read by the host with a GET_REPORT (Feature) request, or as an input
+/// report sent every `STATS_INTERVAL`.
+#[gen_hid_descriptor(
+	(collection = APPLICATION, usage_page = 0xFF00, usage = 0x01) = {
+		(usage = 0x01,) = {
+			#[item_settings data,variable,absolute] counters=feature;
+		};
+		(usage = 0x02,) = {
+			#[item_settings data,variable,absolute] sent_counters=input;
+		};
+	}
+)]
+pub struct LinkStatsReport {
+	pub counters:      [u8; 24],
+	pub sent_counters: [u8; 24],
+}
+
+/// Vendor-defined report for updating the other half: the host's update
+/// tool writes the image as output reports (see `update`) and polls the
+/// progress with GET_REPORT (Feature).
+#[cfg(feature = "split-update")]
+#[gen_hid_descriptor(
+	(collection = APPLICATION, usage_page = 0xFF00, usage = 0x02) = {
+		(usage = 0x01,) = {
+			#[item_settings data,variable,absolute] data=output;
+		};
+		(usage = 0x02,) = {
+			#[item_settings data,variable,absolute] status=feature;
+		};
+	}
+)]
+pub struct UpdateReport {
+	pub data:   [u8; 64],
+	pub status: [u8; 6],
 }
 
 pub struct UsbConfig {
@@ -31,16 +170,24 @@ pub async fn usb_task(config: UsbConfig) -> ! {
 	config.serial_number = Some("ERROR_UNPLUG_YOUR_EXISTENCE");
 	config.max_power = 100;
 	config.max_packet_size_0 = 64;
+	config.supports_remote_wakeup = true;
 
 	let mut config_descriptor = [0; 256];
 	let mut bos_descriptor = [0; 256];
 	// Microsoft compatible descriptor
 	let mut msos_descriptor = [0; 256];
 	let mut control_buf = [0; 64];
-	let mut device_handler = MyDeviceHandler::new();
+	let mut device_handler = MyDeviceHandler;
+	let mut keyboard_handler = HidHandler::new(KeyboardReport::desc(), &KEYBOARD_BOOT, true);
+	let mut media_handler = HidHandler::new(ConsumerReport::desc(), &CONSUMER_BOOT, false);
+	let mut system_handler = HidHandler::new(SystemControlReport::desc(), &SYSTEM_BOOT, false);
+	let mut stats_handler = LinkStatsRequestHandler;
+	#[cfg(feature = "split-update")]
+	let mut update_handler = UpdateRequestHandler;
 
-	let mut state = State::new();
-	let mut media_state = State::new();
+	let mut stats_state = State::new();
+	#[cfg(feature = "split-update")]
+	let mut update_state = State::new();
 
 	let mut builder = Builder::new(
 		driver,
@@ -53,94 +200,414 @@ pub async fn usb_task(config: UsbConfig) -> ! {
 
 	builder.handler(&mut device_handler);
 
+	let (mut hid, hid_out) = build_hid(&mut builder, &mut keyboard_handler, 5);
+	let (mut media_hid, _) = build_hid(&mut builder, &mut media_handler, 2);
+	let (mut system_hid, _) = build_hid(&mut builder, &mut system_handler, 10);
+
 	let config = embassy_usb::class::hid::Config {
-		report_descriptor: KeyboardReport::desc(),
-		request_handler:   None,
-		poll_ms:           5,
+		report_descriptor: LinkStatsReport::desc(),
+		request_handler:   Some(&mut stats_handler),
+		poll_ms:           255,
 		max_packet_size:   64,
 	};
-	let mut hid = HidWriter::<_, 16>::new(&mut builder, &mut state, config);
+	let mut stats_hid = HidWriter::<_, 24>::new(&mut builder, &mut stats_state, config);
 
+	#[cfg(feature = "split-update")]
 	let config = embassy_usb::class::hid::Config {
-		report_descriptor: MediaKeyboardReport::desc(),
-		request_handler:   None,
-		poll_ms:           2,
+		report_descriptor: UpdateReport::desc(),
+		request_handler:   Some(&mut update_handler),
+		poll_ms:           1,
 		max_packet_size:   64,
 	};
-	let mut media_hid = HidWriter::<_, 4>::new(&mut builder, &mut media_state, config);
+	#[cfg(feature = "split-update")]
+	let update_hid = HidReaderWriter::<_, 64, 1>::new(&mut builder, &mut update_state, config);
 
 	let mut usb = builder.build();
 
-	let usb_fut = usb.run();
+	let usb_fut = async {
+		loop {
+			usb.run_until_suspend().await;
+			// Only keys pressed while suspended should wake the host.
+			REMOTE_WAKEUP.reset();
+			match select(usb.wait_resume(), REMOTE_WAKEUP.wait()).await {
+				Either::First(()) => {}
+				Either::Second(()) => {
+					// Fails if the host hasn't enabled remote wakeup; nothing to do then.
+					usb.remote_wakeup().await.ok();
+				}
+			}
+		}
+	};
 
 	let in_fut = async {
+		let mut consumer_usages = [0_u16; 4];
+		let mut system_usage = 0_u8;
+
 		loop {
 			let event = OUTGOING.receive().await;
 
 			match event {
 				Event::Update(keycodes, modifier) => {
-					let report = KeyboardReport {
-						keycodes,
-						modifier,
-						leds: 0,
-						reserved: 0,
-					};
+					// `KeyboardReport` is laid out like the boot report, so
+					// this is what both protocols expect.
+					let mut report = [0; 8];
+					report[0] = modifier;
+					report[2..].copy_from_slice(&keycodes);
 
-					match hid.write_serialize(&report).await {
+					match hid.write(&report).await {
 						Ok(()) => {}
 						Err(_) => panic!(),
 					};
 				}
 				Event::Consumer(usage_id) => {
-					let report = MediaKeyboardReport { usage_id };
+					if press_consumer(&mut consumer_usages, usage_id) {
+						write_consumer(&mut media_hid, consumer_usages).await;
+						release_consumer(&mut consumer_usages, usage_id);
+						write_consumer(&mut media_hid, consumer_usages).await;
+					}
+				}
+				Event::ConsumerDown(usage_id) => {
+					if press_consumer(&mut consumer_usages, usage_id) {
+						write_consumer(&mut media_hid, consumer_usages).await;
+					}
+				}
+				Event::ConsumerUp(usage_id) => {
+					if release_consumer(&mut consumer_usages, usage_id) {
+						write_consumer(&mut media_hid, consumer_usages).await;
+					}
+				}
+				Event::SystemDown(usage_id) => {
+					if system_usage != usage_id {
+						system_usage = usage_id;
+						write_system(&mut system_hid, system_usage).await;
+					}
+				}
+				Event::SystemUp(usage_id) => {
+					if system_usage == usage_id {
+						system_usage = 0;
+						write_system(&mut system_hid, system_usage).await;
+					}
+				}
+			}
+		}
+	};
 
-					match media_hid.write_serialize(&report).await {
-						Ok(()) => {}
-						Err(_) => panic!(),
-					};
+	// Hosts may also send the LEDs here rather than with SET_REPORT.
+	let out_fut = async {
+		let mut hid_out = hid_out.unwrap();
+		let mut report = [0; 8];
 
-					let report = MediaKeyboardReport { usage_id: 0 };
+		loop {
+			hid_out.wait_enabled().await;
 
-					match media_hid.write_serialize(&report).await {
-						Ok(()) => {}
-						Err(_) => panic!(),
-					};
+			while let Ok(len) = hid_out.read(&mut report).await {
+				if len > 0 {
+					set_host_leds(report[0]);
 				}
 			}
 		}
 	};
 
-	join(usb_fut, in_fut).await;
+	#[cfg(feature = "split-update")]
+	let update_fut = async {
+		let (mut update_reader, _update_writer) = update_hid.split();
+		let mut report = [0; 64];
+
+		loop {
+			// Short reports are padded with zeroes.
+			if let Ok(len) = update_reader.read(&mut report).await {
+				report[len..].fill(0);
+				crate::update::FROM_HOST.send(report).await;
+			}
+		}
+	};
+	#[cfg(not(feature = "split-update"))]
+	let update_fut = core::future::pending::<()>();
+
+	let stats_fut = async {
+		loop {
+			Timer::after(STATS_INTERVAL).await;
+			// Fails while the bus is unconfigured; there's always a next time.
+			stats_hid.write(&stats_report()).await.ok();
+		}
+	};
+
+	join5(usb_fut, in_fut, out_fut, update_fut, stats_fut).await;
 
 	panic!();
 }
 
-struct MyDeviceHandler {
-	configured: AtomicBool,
+async fn write_consumer(hid: &mut impl EndpointIn, usage_ids: [u16; 4]) {
+	// Only the keyboard has a boot report format; a host
+	// that asked for boot protocol here can't parse ours.
+	if CONSUMER_BOOT.load(Ordering::Relaxed) {
+		return;
+	}
+
+	let mut report = [0; 8];
+	for (bytes, usage_id) in report.chunks_exact_mut(2).zip(usage_ids) {
+		bytes.copy_from_slice(&usage_id.to_le_bytes());
+	}
+
+	match hid.write(&report).await {
+		Ok(()) => {}
+		Err(_) => panic!(),
+	};
+}
+
+async fn write_system(hid: &mut impl EndpointIn, usage_id: u8) {
+	if SYSTEM_BOOT.load(Ordering::Relaxed) {
+		return;
+	}
+
+	match hid.write(&[usage_id]).await {
+		Ok(()) => {}
+		Err(_) => panic!(),
+	};
+}
+
+fn press_consumer(usages: &mut [u16; 4], usage_id: u16) -> bool {
+	if usage_id == 0 || usages.contains(&usage_id) {
+		return false;
+	}
+
+	for slot in usages.iter_mut() {
+		if *slot == 0 {
+			*slot = usage_id;
+			return true;
+		}
+	}
+
+	false
+}
+
+fn release_consumer(usages: &mut [u16; 4], usage_id: u16) -> bool {
+	if usage_id == 0 {
+		return false;
+	}
+
+	let mut update = false;
+	for slot in usages.iter_mut() {
+		if *slot == usage_id {
+			*slot = 0;
+			update = true;
+		}
+	}
+	update
+}
+
+fn set_host_leds(leds: u8) {
+	if HOST_LEDS.swap(leds, Ordering::Relaxed) != leds {
+		state::DIRTY.signal(());
+	}
+}
+
+/// Adds a HID interface for `handler`'s reports, returning its IN endpoint
+/// and, for the keyboard, an OUT endpoint for the LEDs.
+///
+/// These are built by hand rather than with embassy's HID class, which
+/// always declares subclass 0 and protocol 0: BIOSes and KVMs only ever
+/// switch to the boot protocol on an interface that declares the boot
+/// subclass and the keyboard protocol.
+fn build_hid<'d>(
+	builder: &mut Builder<'d, Driver<'d, USB>>,
+	handler: &'d mut HidHandler,
+	poll_ms: u8,
+) -> (
+	<Driver<'d, USB> as embassy_usb::driver::Driver<'d>>::EndpointIn,
+	Option<<Driver<'d, USB> as embassy_usb::driver::Driver<'d>>::EndpointOut>,
+) {
+	let (subclass, protocol) = if handler.keyboard {
+		(HID_SUBCLASS_BOOT, HID_PROTOCOL_KEYBOARD)
+	} else {
+		(0, 0)
+	};
+
+	let mut function = builder.function(USB_CLASS_HID, subclass, protocol);
+	let mut interface = function.interface();
+	handler.interface = interface.interface_number().0 as u16;
+
+	let mut alt = interface.alt_setting(USB_CLASS_HID, subclass, protocol, None);
+	alt.descriptor(HID_DESC_HID, &handler.hid_descriptor()[2..]);
+
+	// Every one of our reports fits in a single packet.
+	let ep_in = alt.endpoint_interrupt_in(8, poll_ms);
+	let ep_out = handler
+		.keyboard
+		.then(|| alt.endpoint_interrupt_out(8, poll_ms));
+
+	drop(function);
+	builder.handler(handler);
+
+	(ep_in, ep_out)
+}
+
+/// Answers the class requests for one of the interfaces from `build_hid`.
+struct HidHandler {
+	interface: u16,
+	report_descriptor: &'static [u8],
+	boot: &'static AtomicBool,
+	/// Whether this is the keyboard, which takes the host's LEDs.
+	keyboard: bool,
+}
+
+impl HidHandler {
+	fn new(report_descriptor: &'static [u8], boot: &'static AtomicBool, keyboard: bool) -> Self {
+		Self {
+			interface: 0,
+			report_descriptor,
+			boot,
+			keyboard,
+		}
+	}
+
+	fn hid_descriptor(&self) -> [u8; 9] {
+		let len = self.report_descriptor.len() as u16;
+		let [len_lo, len_hi] = len.to_le_bytes();
+
+		[
+			9,
+			HID_DESC_HID,
+			// HID 1.11
+			0x11,
+			0x01,
+			// Country code, number of descriptors
+			0x00,
+			0x01,
+			HID_DESC_REPORT,
+			len_lo,
+			len_hi,
+		]
+	}
+
+	fn is_ours(&self, req: &Request) -> bool {
+		req.recipient == Recipient::Interface && req.index == self.interface
+	}
 }
 
-impl MyDeviceHandler {
-	fn new() -> Self {
-		MyDeviceHandler {
-			configured: AtomicBool::new(false),
+impl Handler for HidHandler {
+	fn reset(&mut self) {
+		// The report protocol is the default after a reset.
+		self.boot.store(false, Ordering::Relaxed);
+	}
+
+	fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+		if req.request_type != RequestType::Class || !self.is_ours(&req) {
+			return None;
 		}
+
+		match req.request {
+			HID_REQ_SET_PROTOCOL => self.boot.store(req.value == 0, Ordering::Relaxed),
+			HID_REQ_SET_REPORT if self.keyboard => {
+				if let Some(&leds) = data.first() {
+					set_host_leds(leds);
+				}
+			}
+			// We only ever report on changes anyway.
+			HID_REQ_SET_IDLE => {}
+			_ => return Some(OutResponse::Rejected),
+		}
+
+		Some(OutResponse::Accepted)
+	}
+
+	fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+		if !self.is_ours(&req) {
+			return None;
+		}
+
+		let response = match (req.request_type, req.request) {
+			(RequestType::Standard, Request::GET_DESCRIPTOR) => {
+				match (req.value >> 8) as u8 {
+					HID_DESC_REPORT => InResponse::Accepted(self.report_descriptor),
+					HID_DESC_HID => {
+						let descriptor = self.hid_descriptor();
+						buf[..descriptor.len()].copy_from_slice(&descriptor);
+						InResponse::Accepted(&buf[..descriptor.len()])
+					}
+					_ => InResponse::Rejected,
+				}
+			}
+			(RequestType::Class, HID_REQ_GET_PROTOCOL) => {
+				buf[0] = if self.boot.load(Ordering::Relaxed) {
+					0
+				} else {
+					1
+				};
+				InResponse::Accepted(&buf[..1])
+			}
+			(RequestType::Class, HID_REQ_GET_IDLE) => {
+				buf[0] = 0;
+				InResponse::Accepted(&buf[..1])
+			}
+			(RequestType::Class, _) => InResponse::Rejected,
+			_ => return None,
+		};
+
+		Some(response)
 	}
 }
 
+struct LinkStatsRequestHandler;
+
+impl RequestHandler for LinkStatsRequestHandler {
+	fn get_report(&mut self, _id: ReportId, buf: &mut [u8]) -> Option<usize> {
+		let report = stats_report();
+		let buf = buf.get_mut(..report.len())?;
+		buf.copy_from_slice(&report);
+		Some(report.len())
+	}
+}
+
+fn stats_report() -> [u8; 24] {
+	let mut report = [0; 24];
+
+	for (chunk, counter) in report.chunks_exact_mut(4).zip(uart::LINK.stats.snapshot()) {
+		chunk.copy_from_slice(&counter.to_le_bytes());
+	}
+
+	report
+}
+
+struct MyDeviceHandler;
+
 impl Handler for MyDeviceHandler {
 	fn enabled(&mut self, _enabled: bool) {
-		self.configured.store(false, Ordering::Relaxed);
+		set_configured(false);
 	}
 
 	fn reset(&mut self) {
-		self.configured.store(false, Ordering::Relaxed);
+		set_configured(false);
 	}
 
 	fn addressed(&mut self, _addr: u8) {
-		self.configured.store(false, Ordering::Relaxed);
+		set_configured(false);
 	}
 
 	fn configured(&mut self, configured: bool) {
-		self.configured.store(configured, Ordering::Relaxed);
+		set_configured(configured);
+	}
+
+	fn suspended(&mut self, suspended: bool) {
+		BUS_SUSPENDED.store(suspended, Ordering::Relaxed);
+		set_suspended(suspended);
+		uart::LINK.try_send(uart::Packet::UsbSuspend(suspended));
+	}
+}
+
+#[cfg(feature = "split-update")]
+struct UpdateRequestHandler;
+
+#[cfg(feature = "split-update")]
+impl RequestHandler for UpdateRequestHandler {
+	fn get_report(&mut self, _id: ReportId, buf: &mut [u8]) -> Option<usize> {
+		let status = crate::update::status();
+
+		if buf.len() < status.len() {
+			return None;
+		}
+
+		buf[..status.len()].copy_from_slice(&status);
+		Some(status.len())
 	}
 }